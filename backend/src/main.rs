@@ -218,6 +218,15 @@ struct Duplicate {
     second_dir_match: Rc<Vec<String>>,
 }
 
+/// duplicates.json, a bare list of groups before dir_compare versioned it,
+/// and a report object holding them since.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Report {
+    Versioned { duplicates: Vec<Duplicate> },
+    Bare(Vec<Duplicate>),
+}
+
 struct CompareWindow {
     files: Rc<Vec<String>>,
     index: usize,
@@ -379,7 +388,10 @@ fn handle_message(message: String, compare_manager: &mut Option<CompareManager>)
                 Ok(mut child_proc) => {
                     child_proc.wait().expect("Command wasn't running");
                     let dups_json = fs::read_to_string("./duplicates.json").unwrap();
-                    let duplicates = serde_json::from_str(&dups_json).unwrap();
+                    let duplicates = match serde_json::from_str(&dups_json).unwrap() {
+                        Report::Versioned { duplicates } => duplicates,
+                        Report::Bare(duplicates) => duplicates,
+                    };
                     *compare_manager = Some(CompareManager::new(duplicates));
                 }
                 Err(e) => eprintln!("Process failed to start: {e}"),
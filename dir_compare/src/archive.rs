@@ -0,0 +1,213 @@
+use flate2::read::GzDecoder;
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use crate::{cancel, hash::hash_reader, File};
+
+/// Joins an archive path with the path of one of its members.
+pub const SEPARATOR: &str = "!/";
+
+enum Kind {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+fn kind_of(path: &Path) -> Option<Kind> {
+    let name = path.file_name()?.to_str()?.to_lowercase();
+    if name.ends_with(".zip") {
+        Some(Kind::Zip)
+    } else if name.ends_with(".tar") {
+        Some(Kind::Tar)
+    } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+        Some(Kind::TarGz)
+    } else {
+        None
+    }
+}
+
+pub fn is_archive(path: &Path) -> bool {
+    kind_of(path).is_some()
+}
+
+/// Tells whether `path` points inside an archive rather than to a real file.
+pub fn is_member(path: &str) -> bool {
+    path.contains(SEPARATOR)
+}
+
+fn member(
+    archive: &str,
+    inner: &str,
+    size: u64,
+    modified: Option<SystemTime>,
+    hash: String,
+) -> File {
+    let inner = inner.trim_start_matches('/');
+    File {
+        name: inner.rsplit('/').next().unwrap_or(inner).to_string(),
+        path: format!("{}{}{}", archive, SEPARATOR, inner),
+        size,
+        modified,
+        hash: Some(hash),
+        decompressed: false,
+        meta: None,
+    }
+}
+
+fn read_tar<R: Read>(reader: R, archive: &str, files: &mut Vec<File>) -> io::Result<()> {
+    let mut tar = tar::Archive::new(reader);
+    for entry in tar.entries()? {
+        if cancel::stopped() {
+            return Err(cancel::stopped_err());
+        }
+        let entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let inner = entry.path()?.to_string_lossy().to_string();
+        let size = entry.header().size()?;
+        let modified = entry
+            .header()
+            .mtime()
+            .ok()
+            .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+        let hash = hash_reader(entry)?;
+        files.push(member(archive, &inner, size, modified, hash));
+    }
+    Ok(())
+}
+
+fn read_zip(path: &Path, archive: &str, files: &mut Vec<File>) -> io::Result<()> {
+    let mut zip = zip::ZipArchive::new(fs::File::open(path)?)?;
+    for idx in 0..zip.len() {
+        if cancel::stopped() {
+            return Err(cancel::stopped_err());
+        }
+        let entry = zip.by_index(idx)?;
+        if !entry.is_file() {
+            continue;
+        }
+        let inner = entry.name().to_string();
+        let size = entry.size();
+        let hash = hash_reader(entry)?;
+        files.push(member(archive, &inner, size, None, hash));
+    }
+    Ok(())
+}
+
+/// Lists the regular files inside a supported archive as virtual files,
+/// hashing their content on the way since it can't be reopened later.
+pub fn read_members(path: &Path, files: &mut Vec<File>) -> io::Result<()> {
+    let archive = path.to_str().expect("Invalid Unicode data");
+    match kind_of(path) {
+        Some(Kind::Zip) => read_zip(path, archive, files),
+        Some(Kind::Tar) => read_tar(fs::File::open(path)?, archive, files),
+        Some(Kind::TarGz) => read_tar(GzDecoder::new(fs::File::open(path)?), archive, files),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use flate2::{write::GzEncoder, Compression};
+    use std::io::Write;
+
+    const MEMBERS: [(&str, &[u8]); 2] = [("lib/foo.so", b"foo bytes"), ("README", b"read me")];
+
+    fn write_tar<W: Write>(writer: W) {
+        let mut tar = tar::Builder::new(writer);
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_size(0);
+        tar.append_data(&mut header, "lib/", io::empty()).unwrap();
+        for (name, content) in MEMBERS {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mtime(1_000_000);
+            tar.append_data(&mut header, name, content).unwrap();
+        }
+        tar.into_inner().unwrap().flush().unwrap();
+    }
+
+    fn write_zip(path: &Path) {
+        let mut zip = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        zip.add_directory("lib/", options).unwrap();
+        for (name, content) in MEMBERS {
+            zip.start_file(name, options).unwrap();
+            zip.write_all(content).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn members(path: &Path) -> Vec<(String, String, u64, String)> {
+        let mut files = Vec::new();
+        read_members(path, &mut files).unwrap();
+        files
+            .into_iter()
+            .map(|f| (f.name, f.path, f.size, f.hash.unwrap()))
+            .collect()
+    }
+
+    fn expected(archive: &Path) -> Vec<(String, String, u64, String)> {
+        MEMBERS
+            .iter()
+            .map(|(inner, content)| {
+                (
+                    inner.rsplit('/').next().unwrap().to_string(),
+                    format!("{}!/{}", archive.display(), inner),
+                    content.len() as u64,
+                    hash_reader(*content).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn lists_the_files_of_each_archive_kind() {
+        let tmp = TempDir::new("archive-kinds");
+        let tar = tmp.path.join("bundle.tar");
+        write_tar(fs::File::create(&tar).unwrap());
+        let tgz = tmp.path.join("bundle.TGZ");
+        write_tar(GzEncoder::new(
+            fs::File::create(&tgz).unwrap(),
+            Compression::fast(),
+        ));
+        let zip = tmp.path.join("bundle.zip");
+        write_zip(&zip);
+
+        for archive in [&tar, &tgz, &zip] {
+            assert!(is_archive(archive));
+            assert_eq!(members(archive), expected(archive), "{:?}", archive);
+        }
+        let mut files = Vec::new();
+        read_members(&tmp.path.join("tar"), &mut files).unwrap();
+        assert!(files.is_empty());
+    }
+
+    #[test]
+    fn keeps_the_mtime_of_tar_members() {
+        let tmp = TempDir::new("archive-mtime");
+        let tar = tmp.path.join("bundle.tar");
+        write_tar(fs::File::create(&tar).unwrap());
+        let mut files = Vec::new();
+        read_members(&tar, &mut files).unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        assert!(files.iter().all(|f| f.modified == Some(mtime)));
+        assert!(files.iter().all(|f| is_member(&f.path)));
+    }
+
+    #[test]
+    fn fails_on_broken_archives() {
+        let tmp = TempDir::new("archive-broken");
+        let file = tmp.file("broken.zip", b"not a zip");
+        let mut files = Vec::new();
+        assert!(read_members(Path::new(&file.path), &mut files).is_err());
+    }
+}
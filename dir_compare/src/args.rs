@@ -0,0 +1,731 @@
+use serde::{Deserialize, Serialize};
+use std::{env, fs, path::PathBuf, process};
+
+use crate::{cancel::Budget, chunks::MAX_CHUNK_SIZE, config, db::Filter, names::NameMatch};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Duplicates,
+    Diff,
+    Watch,
+    Manifest,
+    Verify,
+    Query,
+    Chunks,
+    Sync,
+    Merge,
+    Review,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchBy {
+    #[default]
+    Name,
+    Content,
+    Image,
+    Text,
+}
+
+/// Which version merge keeps of a path both dirs hold with different content.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Conflict {
+    Newest,
+    /// Keep the first dir's version at the path and the second's next to it.
+    #[default]
+    Both,
+    Left,
+    Right,
+}
+
+/// Where the copies of a duplicate group live, when two dirs are compared.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    IntraRoot,
+    CrossRoot,
+}
+
+impl Scope {
+    pub fn of(mut roots: impl Iterator<Item = usize>) -> Self {
+        let first = roots.next();
+        if roots.all(|root| Some(root) == first) {
+            Scope::IntraRoot
+        } else {
+            Scope::CrossRoot
+        }
+    }
+
+    /// Splits a group of `(root, item)`, sorted by root, into the groups kept
+    /// for `scope`: one per root for intra-root, the whole group when it
+    /// spans roots for cross-root, and the whole group when no scope is
+    /// given. Groups left with a single item are dropped.
+    pub fn split<T>(scope: Option<Scope>, group: Vec<(usize, T)>) -> Vec<Vec<(usize, T)>> {
+        let mut groups: Vec<Vec<(usize, T)>> = Vec::new();
+        match scope {
+            Some(Scope::IntraRoot) => {
+                for (root, item) in group {
+                    match groups.last_mut() {
+                        Some(last) if last[0].0 == root => last.push((root, item)),
+                        _ => groups.push(vec![(root, item)]),
+                    }
+                }
+            }
+            Some(Scope::CrossRoot)
+                if Scope::of(group.iter().map(|(root, _)| *root)) == Scope::IntraRoot => {}
+            _ => groups.push(group),
+        }
+        groups.retain(|group| group.len() > 1);
+        groups
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Options {
+    pub match_by: MatchBy,
+    pub name_match: NameMatch,
+    /// Look inside zip and tar archives as if they were dirs.
+    pub archives: bool,
+    /// Hash gzip, zstd, xz and bzip2 files on their decompressed content.
+    pub decompress: bool,
+    /// Fingerprint every dir during the walk and report identical subtrees.
+    pub dirs: bool,
+    /// Keep file matches that lie inside reported identical subtrees.
+    pub all_files: bool,
+    /// Only keep groups of this scope, split per root for intra-root.
+    pub scope: Option<Scope>,
+    /// Most bits two perceptual image hashes may differ in to be grouped.
+    pub max_distance: u32,
+    /// Least estimated shingle similarity of two text files to be grouped.
+    pub threshold: f64,
+    /// Compare mode bits, ownership, mtimes and extended attributes of the
+    /// files in each group, or of the files paired by a diff.
+    pub metadata: bool,
+    /// Seconds two mtimes may differ by and still count as equal.
+    pub mtime_tolerance: f64,
+    pub budget: Budget,
+    /// Average chunk size aimed at by content-defined chunking.
+    pub chunk_size: u64,
+    /// How many of the largest groups and breakdown entries to print.
+    pub top: usize,
+    /// Keep about this many bytes of the file index in memory, spilling the
+    /// rest to disk.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_memory: Option<u64>,
+    /// Don't draw the live progress line on a terminal.
+    #[serde(skip)]
+    pub no_progress: bool,
+    /// File descriptor to write JSON progress events to.
+    #[serde(skip)]
+    pub progress_json: Option<i32>,
+    /// Journal of the dirs walked and files hashed, to resume from.
+    #[serde(skip)]
+    pub checkpoint: Option<PathBuf>,
+    /// Only report what sync or merge would copy.
+    pub dry_run: bool,
+    /// How merge resolves paths with different content in both dirs.
+    pub conflict: Conflict,
+    /// Dir merge writes to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<PathBuf>,
+    /// Don't sync files whose content is already in the second root.
+    pub skip_existing_content: bool,
+    /// SQLite file to write the scanned files and duplicate groups to, for
+    /// `query`.
+    #[serde(skip)]
+    pub sqlite: Option<PathBuf>,
+    /// Replace an existing SQLite index.
+    #[serde(skip)]
+    pub force: bool,
+    /// Which groups `query` prints.
+    #[serde(skip)]
+    pub filter: Filter,
+    /// Shell command to run on every duplicate found in watch mode.
+    #[serde(skip)]
+    pub on_duplicate: Option<String>,
+    /// Checkpoint to continue a stopped scan from.
+    #[serde(skip)]
+    pub resume: Option<PathBuf>,
+    /// Config profile the options were taken from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// Config files that were read, in the order they were applied.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub config_files: Vec<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            match_by: MatchBy::default(),
+            name_match: NameMatch::default(),
+            archives: false,
+            decompress: false,
+            dirs: false,
+            all_files: false,
+            scope: None,
+            max_distance: 10,
+            threshold: 0.7,
+            metadata: false,
+            mtime_tolerance: 0.0,
+            budget: Budget::default(),
+            chunk_size: 64 * 1024,
+            top: 10,
+            max_memory: None,
+            no_progress: false,
+            progress_json: None,
+            checkpoint: None,
+            dry_run: false,
+            conflict: Conflict::default(),
+            destination: None,
+            skip_existing_content: false,
+            sqlite: None,
+            force: false,
+            filter: Filter::default(),
+            resume: None,
+            on_duplicate: None,
+            profile: None,
+            config_files: Vec::new(),
+        }
+    }
+}
+
+pub struct Args {
+    pub mode: Mode,
+    pub paths: Vec<PathBuf>,
+    pub options: Options,
+}
+
+const USAGE: &str = "\
+Usage: dir_compare [diff|watch] <dir1> [dir2] [options]
+       dir_compare manifest <dir> [options]
+       dir_compare verify <dir> [checksum file] [options]
+       dir_compare query <index> [filters]
+       dir_compare chunks <dir1> [dir2] [options]
+       dir_compare sync <from> <to> [options]
+       dir_compare merge <dir1> <dir2> <destination> [options]
+       dir_compare review [report]
+
+manifest hashes every file of a dir and writes their listing to
+./manifest.json. A manifest can be given in place of a dir to compare with
+the tree it lists, by name or by content.
+
+verify checks the files of a dir against the SHA256SUMS, MD5SUMS, .sha256,
+.md5 and .sfv files in it, or against the checksum file given, and writes
+the missing, extra and corrupted files to ./verify.json.
+
+query prints the duplicate groups of an index written with --sqlite,
+largest first. A group is printed when one of its files passes every
+filter. The index also lists the scanned files that are in no group.
+
+sync copies the files only in <from> to the same place under <to>, keeping
+their mode, owner, mtime and xattrs, and writes what it did to ./sync.json.
+Files moved or renamed under <to> are copied to their old place too.
+
+merge writes every path of both dirs to a new destination and what it did
+to ./merge.json. Each content is copied once, and the other paths holding
+it are hard links to that copy. A path both dirs hold with different
+content is resolved by --conflict.
+
+review browses the duplicate groups of a report, ./duplicates.json by
+default, largest waste first, with a preview of the selected file. Files
+marked to keep, delete or replace with a hard link to the kept copy are
+saved as an action plan to ./plan.json, nothing is changed on disk.
+
+chunks splits every file into content-defined chunks and reports how many
+bytes a chunk-level dedup store would hold, to ./chunks.json.
+
+watch keeps running after the first scan and reports files that turn up as
+duplicates of files already there, until interrupted.
+
+Exits with 0 when nothing was found, 1 when duplicates, differences or
+failed checks were found, 2 when the run failed and 3 when the report is
+partial, because the scan was stopped or some files couldn't be read. A
+second interrupt quits right away with 130, without a report.
+
+Options are read from ~/.config/dir_compare/config.toml, then from
+./dir_compare.toml, then from the command line. Config keys are named like
+the flags, and [profiles.<name>] tables hold settings picked with --profile,
+which win over the top-level settings of both files. A flag a config file
+turns on is turned off again with --no-<flag>, and no-progress with
+--progress.
+
+Options:
+  --profile <name>        apply a profile from the config files
+  --match <mode>          name (default), content, image or text
+  --ignore-case           match names regardless of case
+  --normalize             match names in their Unicode NFC form
+  --stem                  match names without their extension
+  --with-size             match names only when sizes are equal too
+  --scope <scope>         intra: only copies within one root, split per
+                          root; cross: only groups spanning roots
+  --archives              scan inside zip, tar and tar.gz files
+  --decompress            compare compressed files by their content
+  --dirs                  report identical subtrees as one duplicate
+  --all-files             keep file matches inside identical subtrees
+  --max-distance <bits>   image hash bits that may differ (default 10)
+  --threshold <0..1>      least text similarity (default 0.7)
+  --metadata              report copies whose mode, owner, mtime or xattrs
+                          differ from the first file of their group, or in
+                          diff mode from the file of the first dir
+  --mtime-tolerance <s>   seconds mtimes may differ by (default 0)
+  --chunk-size <size>     average chunk size of chunks, 1K to 1G (default 64K)
+  --top <n>               largest groups or files to list (default 10)
+  --max-time <secs>       stop after this many seconds
+  --max-files <n>         stop after walking this many files
+  --max-bytes <size>      stop after hashing this many bytes (K, M, G, T)
+  --max-memory <size>     keep the file index on disk beyond this size,
+                          only with --match content
+  --no-progress           don't show progress on the terminal
+  --progress-json <fd>    write JSON progress events to a file descriptor
+  --on-duplicate <cmd>    in watch mode, run a shell command for each new
+                          duplicate with $1 the new file and the files it
+                          duplicates after it
+  --dry-run               only report what sync or merge would copy
+  --conflict <policy>     what merge keeps of a path that differs: newest,
+                          both (default, the second with a .right suffix),
+                          left or right
+  --skip-existing-content don't sync files whose content is already
+                          somewhere under <to>
+  --sqlite <file>         also write the scanned files and duplicate groups
+                          to a SQLite index
+  --force                 replace the --sqlite index if it exists
+  --checkpoint <file>     journal scan progress to a file, flushed every
+                          30 seconds
+  --resume <file>         continue a scan from a checkpoint, putting back
+                          the dirs it finished without reading them again,
+                          and journal to it unless --checkpoint is given
+
+Query filters:
+  --under <path>          groups with a file under this path
+  --min-size <size>       groups of files at least this large (K, M, G, T)
+  --ext <ext>             groups with a file of this extension
+  --root <path>           groups with a file in this scanned root";
+
+pub fn parse_match(value: Option<String>) -> Result<MatchBy, String> {
+    match value.as_deref() {
+        Some("name") => Ok(MatchBy::Name),
+        Some("content") => Ok(MatchBy::Content),
+        Some("image") => Ok(MatchBy::Image),
+        Some("text") => Ok(MatchBy::Text),
+        Some(other) => Err(format!("Unknown match mode: {}", other)),
+        None => Err("--match needs a value".into()),
+    }
+}
+
+pub fn parse_conflict(value: Option<String>) -> Result<Conflict, String> {
+    match value.as_deref() {
+        Some("newest") => Ok(Conflict::Newest),
+        Some("both") => Ok(Conflict::Both),
+        Some("left") => Ok(Conflict::Left),
+        Some("right") => Ok(Conflict::Right),
+        Some(other) => Err(format!("Unknown conflict policy: {}", other)),
+        None => Err("--conflict needs a value".into()),
+    }
+}
+
+pub fn parse_scope(value: Option<String>) -> Result<Option<Scope>, String> {
+    match value.as_deref() {
+        Some("all") => Ok(None),
+        Some("intra") => Ok(Some(Scope::IntraRoot)),
+        Some("cross") => Ok(Some(Scope::CrossRoot)),
+        Some(other) => Err(format!("Unknown scope: {}", other)),
+        None => Err("--scope needs a value".into()),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    value
+        .as_deref()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| format!("{} needs a number", flag))
+}
+
+fn parse_path(flag: &str, value: Option<String>) -> Result<PathBuf, String> {
+    value
+        .map(PathBuf::from)
+        .ok_or_else(|| format!("{} needs a file", flag))
+}
+
+/// Parses a byte count with an optional binary K, M, G or T suffix.
+pub fn parse_size(flag: &str, value: Option<String>) -> Result<u64, String> {
+    let err = || format!("{} needs a size", flag);
+    let value = value.ok_or_else(err)?;
+    let (digits, shift) = match value.to_uppercase().chars().last() {
+        Some('K') => (&value[..value.len() - 1], 10),
+        Some('M') => (&value[..value.len() - 1], 20),
+        Some('G') => (&value[..value.len() - 1], 30),
+        Some('T') => (&value[..value.len() - 1], 40),
+        _ => (value.as_str(), 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(err)
+}
+
+/// Checks that `--sqlite` is only given when looking for duplicates, and
+/// only names an existing file along with `--force`.
+fn check_sqlite(mode: Mode, options: &Options) -> Result<(), String> {
+    if options.sqlite.is_some() && mode != Mode::Duplicates {
+        return Err("--sqlite only works when looking for duplicates".into());
+    }
+    if let Some(path) = options.sqlite.as_ref().filter(|path| path.exists()) {
+        if !options.force {
+            return Err(format!(
+                "Index already exists: {}. Pass --force to replace it",
+                path.display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+pub fn parse_args() -> Result<Args, String> {
+    let raw: Vec<String> = env::args().skip(1).collect();
+    let profile = raw
+        .windows(2)
+        .find(|pair| pair[0] == "--profile")
+        .map(|pair| pair[1].as_str());
+    let mut options = config::load(profile)?;
+    let mut positional: Vec<String> = Vec::new();
+
+    let mut raw = raw.into_iter();
+    while let Some(arg) = raw.next() {
+        match arg.as_str() {
+            "--match" => options.match_by = parse_match(raw.next())?,
+            "--ignore-case" => options.name_match.ignore_case = true,
+            "--normalize" => options.name_match.normalize = true,
+            "--stem" => options.name_match.stem = true,
+            "--with-size" => options.name_match.with_size = true,
+            "--scope" => options.scope = parse_scope(raw.next())?,
+            "--archives" => options.archives = true,
+            "--decompress" => options.decompress = true,
+            "--dirs" => options.dirs = true,
+            "--all-files" => options.all_files = true,
+            "--max-distance" => options.max_distance = parse_number(&arg, raw.next())?,
+            "--threshold" => options.threshold = parse_number(&arg, raw.next())?,
+            "--metadata" => options.metadata = true,
+            "--mtime-tolerance" => options.mtime_tolerance = parse_number(&arg, raw.next())?,
+            "--chunk-size" => options.chunk_size = parse_size(&arg, raw.next())?,
+            "--top" => options.top = parse_number(&arg, raw.next())?,
+            "--max-time" => options.budget.max_secs = Some(parse_number(&arg, raw.next())?),
+            "--max-files" => options.budget.max_files = Some(parse_number(&arg, raw.next())?),
+            "--max-bytes" => options.budget.max_bytes = Some(parse_size(&arg, raw.next())?),
+            "--max-memory" => options.max_memory = Some(parse_size(&arg, raw.next())?),
+            "--no-progress" => options.no_progress = true,
+            "--progress" => options.no_progress = false,
+            "--progress-json" => options.progress_json = Some(parse_number(&arg, raw.next())?),
+            "--profile" => {
+                raw.next().ok_or("--profile needs a name")?;
+            }
+            "--on-duplicate" => {
+                options.on_duplicate = Some(raw.next().ok_or("--on-duplicate needs a command")?)
+            }
+            "--dry-run" => options.dry_run = true,
+            "--conflict" => options.conflict = parse_conflict(raw.next())?,
+            "--skip-existing-content" => options.skip_existing_content = true,
+            "--sqlite" => options.sqlite = Some(parse_path(&arg, raw.next())?),
+            "--force" => options.force = true,
+            "--under" => options.filter.under = Some(raw.next().ok_or("--under needs a path")?),
+            "--min-size" => options.filter.min_size = Some(parse_size(&arg, raw.next())?),
+            "--ext" => options.filter.ext = Some(raw.next().ok_or("--ext needs an extension")?),
+            "--root" => options.filter.root = Some(raw.next().ok_or("--root needs a path")?),
+            "--checkpoint" => options.checkpoint = Some(parse_path(&arg, raw.next())?),
+            "--resume" => options.resume = Some(parse_path(&arg, raw.next())?),
+            "--no-ignore-case" => options.name_match.ignore_case = false,
+            "--no-normalize" => options.name_match.normalize = false,
+            "--no-stem" => options.name_match.stem = false,
+            "--no-with-size" => options.name_match.with_size = false,
+            "--no-archives" => options.archives = false,
+            "--no-decompress" => options.decompress = false,
+            "--no-dirs" => options.dirs = false,
+            "--no-all-files" => options.all_files = false,
+            "--no-metadata" => options.metadata = false,
+            "--no-dry-run" => options.dry_run = false,
+            "--no-skip-existing-content" => options.skip_existing_content = false,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            flag if flag.starts_with("--") => {
+                return Err(format!("Unknown option: {}\n{}", flag, USAGE))
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    let mode = match positional.first().map(String::as_str) {
+        Some("diff") => {
+            positional.remove(0);
+            Mode::Diff
+        }
+        Some("watch") => {
+            positional.remove(0);
+            Mode::Watch
+        }
+        Some("manifest") => {
+            positional.remove(0);
+            Mode::Manifest
+        }
+        Some("verify") => {
+            positional.remove(0);
+            Mode::Verify
+        }
+        Some("query") => {
+            positional.remove(0);
+            Mode::Query
+        }
+        Some("chunks") => {
+            positional.remove(0);
+            Mode::Chunks
+        }
+        Some("sync") => {
+            positional.remove(0);
+            Mode::Sync
+        }
+        Some("merge") => {
+            positional.remove(0);
+            Mode::Merge
+        }
+        Some("review") => {
+            positional.remove(0);
+            Mode::Review
+        }
+        _ => Mode::Duplicates,
+    };
+    let max_args = if mode == Mode::Merge { 3 } else { 2 };
+    if let Some(extra) = positional.get(max_args) {
+        return Err(format!("Unexpected argument: {}", extra));
+    }
+    let mut args = positional;
+    if mode == Mode::Merge {
+        if args.len() != 3 {
+            return Err("Provide 2 dirs to merge and a destination...".into());
+        }
+        let destination = PathBuf::from(args.pop().unwrap_or_default());
+        let empty = fs::read_dir(&destination).is_ok_and(|mut entries| entries.next().is_none());
+        if destination.exists() && !empty {
+            return Err(format!(
+                "Destination is not an empty dir: {}",
+                destination.display()
+            ));
+        }
+        options.destination = Some(destination);
+    }
+
+    if mode == Mode::Diff && args.len() != 2 {
+        return Err("Provide 2 dirs to diff...".into());
+    }
+    if mode == Mode::Sync && args.len() != 2 {
+        return Err("Provide 2 dirs to sync...".into());
+    }
+    if mode == Mode::Manifest && args.len() != 1 {
+        return Err("Provide 1 dir to list...".into());
+    }
+    if mode == Mode::Query && args.len() != 1 {
+        return Err("Provide 1 index to query...".into());
+    }
+    if mode == Mode::Review {
+        if args.len() > 1 {
+            return Err("Provide 1 report to review...".into());
+        }
+        if args.is_empty() {
+            args.push("./duplicates.json".into());
+        }
+    }
+    check_sqlite(mode, &options)?;
+    if mode == Mode::Verify && args.is_empty() {
+        return Err("Provide 1 dir to verify...".into());
+    }
+    if args.is_empty() {
+        return Err("Provide 1 or 2 arguments...".into());
+    }
+    if mode == Mode::Watch
+        && (!matches!(options.match_by, MatchBy::Name | MatchBy::Content)
+            || options.dirs
+            || options.max_memory.is_some()
+            || options.scope.is_some())
+    {
+        return Err(
+            "watch only works with --match name or content, without --dirs, --max-memory or --scope"
+                .into(),
+        );
+    }
+    if mode == Mode::Verify && (options.dirs || options.max_memory.is_some()) {
+        return Err("verify doesn't work with --dirs or --max-memory".into());
+    }
+    if mode == Mode::Chunks && (options.dirs || options.max_memory.is_some()) {
+        return Err("chunks doesn't work with --dirs or --max-memory".into());
+    }
+    if !(1024..=MAX_CHUNK_SIZE).contains(&options.chunk_size) {
+        return Err("--chunk-size must be between 1K and 1G".into());
+    }
+    if !(0.0..=1.0).contains(&options.threshold) {
+        return Err("--threshold must be between 0 and 1".into());
+    }
+    if options.mtime_tolerance.is_nan() || options.mtime_tolerance < 0.0 {
+        return Err("--mtime-tolerance must be 0 or more seconds".into());
+    }
+    if options.max_memory.is_some()
+        && (mode == Mode::Diff
+            || mode == Mode::Sync
+            || mode == Mode::Merge
+            || options.match_by != MatchBy::Content
+            || options.dirs
+            || options.metadata)
+    {
+        return Err(
+            "--max-memory only works with --match content, without --dirs or --metadata".into(),
+        );
+    }
+
+    let mut skip_count = 0;
+    if args.len() > 1 && args[0] == args[1] {
+        if matches!(mode, Mode::Diff | Mode::Sync | Mode::Merge) {
+            return Err("Can't diff a dir with itself".into());
+        }
+        skip_count = 1;
+    }
+
+    let paths = args
+        .into_iter()
+        .skip(skip_count)
+        .enumerate()
+        .map(|(idx, arg)| {
+            let p = PathBuf::from(arg);
+            // Files are taken as manifests, which only hold names and hashes,
+            // or as the checksum file to verify a dir with.
+            let manifest_ok = !matches!(
+                mode,
+                Mode::Watch
+                    | Mode::Manifest
+                    | Mode::Verify
+                    | Mode::Chunks
+                    | Mode::Sync
+                    | Mode::Merge
+            )
+                && matches!(options.match_by, MatchBy::Name | MatchBy::Content)
+                && !options.dirs
+                && !options.metadata;
+            let file_arg = match mode {
+                Mode::Verify => idx == 1,
+                Mode::Query | Mode::Review => true,
+                _ => false,
+            };
+            if !p.exists() {
+                Err(format!("Path does not exist: {}", p.display()))
+            } else if file_arg && !p.is_file() {
+                Err(format!("Path is not a file: {}", p.display()))
+            } else if p.is_file() && !manifest_ok && matches!(mode, Mode::Duplicates | Mode::Diff) {
+                Err(format!(
+                    "Path is not a dir: {}. Manifests can only be matched by name or content, without --dirs or --metadata",
+                    p.display()
+                ))
+            } else if !p.is_dir() && !(p.is_file() && (file_arg || manifest_ok)) {
+                Err(format!("Path is not a dir: {}", p.display()))
+            } else {
+                Ok(p)
+            }
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(Args {
+        mode,
+        paths,
+        options,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn size(value: &str) -> Result<u64, String> {
+        parse_size("--min-size", Some(value.to_string()))
+    }
+
+    #[test]
+    fn parses_sizes_with_suffixes() {
+        assert_eq!(size("0"), Ok(0));
+        assert_eq!(size("1500"), Ok(1500));
+        assert_eq!(size("4K"), Ok(4 << 10));
+        assert_eq!(size("4k"), Ok(4 << 10));
+        assert_eq!(size("3M"), Ok(3 << 20));
+        assert_eq!(size("2G"), Ok(2 << 30));
+        assert_eq!(size("1T"), Ok(1 << 40));
+    }
+
+    #[test]
+    fn tells_the_scope_of_roots() {
+        assert_eq!(Scope::of([0, 0, 0].into_iter()), Scope::IntraRoot);
+        assert_eq!(Scope::of([1].into_iter()), Scope::IntraRoot);
+        assert_eq!(Scope::of([0, 0, 1].into_iter()), Scope::CrossRoot);
+    }
+
+    #[test]
+    fn splits_groups_per_scope() {
+        let group = || vec![(0, "a/x"), (0, "a/sub/x"), (1, "b/x")];
+        let split = |scope| Scope::split(scope, group());
+        assert_eq!(split(None), [group()]);
+        assert_eq!(split(Some(Scope::CrossRoot)), [group()]);
+        assert_eq!(
+            split(Some(Scope::IntraRoot)),
+            [vec![(0, "a/x"), (0, "a/sub/x")]]
+        );
+
+        let within = vec![(1, "b/x"), (1, "b/y")];
+        assert!(Scope::split(Some(Scope::CrossRoot), within.clone()).is_empty());
+        assert_eq!(
+            Scope::split(Some(Scope::IntraRoot), within.clone()),
+            [within]
+        );
+
+        let spread = vec![(0, "a/x"), (1, "b/x"), (1, "b/y"), (2, "c/x"), (2, "c/y")];
+        assert_eq!(
+            Scope::split(Some(Scope::IntraRoot), spread),
+            [vec![(1, "b/x"), (1, "b/y")], vec![(2, "c/x"), (2, "c/y")]]
+        );
+    }
+
+    #[test]
+    fn only_replaces_an_index_with_force() {
+        let tmp = TempDir::new("args-sqlite");
+        let existing = tmp.file("index.db", b"");
+        let mut options = Options {
+            sqlite: Some(tmp.path.join("new.db")),
+            ..Options::default()
+        };
+        assert_eq!(check_sqlite(Mode::Duplicates, &options), Ok(()));
+        assert_eq!(
+            check_sqlite(Mode::Diff, &options),
+            Err("--sqlite only works when looking for duplicates".to_string())
+        );
+
+        options.sqlite = Some(PathBuf::from(&existing.path));
+        assert_eq!(
+            check_sqlite(Mode::Duplicates, &options),
+            Err(format!(
+                "Index already exists: {}. Pass --force to replace it",
+                existing.path
+            ))
+        );
+        options.force = true;
+        assert_eq!(check_sqlite(Mode::Duplicates, &options), Ok(()));
+    }
+
+    #[test]
+    fn rejects_bad_sizes() {
+        let err = Err("--min-size needs a size".to_string());
+        assert_eq!(parse_size("--min-size", None), err);
+        assert_eq!(size(""), err);
+        assert_eq!(size("K"), err);
+        assert_eq!(size("1.5G"), err);
+        assert_eq!(size("-1"), err);
+        assert_eq!(size("4KB"), err);
+        assert_eq!(size("16777216T"), err);
+    }
+}
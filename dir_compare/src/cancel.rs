@@ -0,0 +1,130 @@
+use serde::Serialize;
+use std::{
+    io, process,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use crate::{progress, spill};
+
+/// Limits after which a scan stops and reports what it found so far.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Budget {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_files: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_bytes: Option<u64>,
+}
+
+static BUDGET: OnceLock<(Budget, Instant)> = OnceLock::new();
+static STOP_REASON: Mutex<Option<&'static str>> = Mutex::new(None);
+
+fn stop(reason: &'static str) {
+    let mut stop_reason = STOP_REASON.lock().unwrap();
+    if stop_reason.is_none() {
+        *stop_reason = Some(reason);
+    }
+}
+
+/// Starts the budget clock and makes SIGINT and SIGTERM stop the scan. A
+/// second signal exits right away.
+pub fn init(budget: &Budget) {
+    BUDGET.get_or_init(|| (budget.clone(), Instant::now()));
+
+    let handler = ctrlc::set_handler(|| {
+        if reason().is_some() {
+            spill::remove_dir();
+            process::exit(130);
+        }
+        eprintln!("\nStopping, press again to quit without a report...");
+        stop("interrupted");
+    });
+    if let Err(e) = handler {
+        eprintln!("Signal handler err: {}", e);
+    }
+}
+
+/// Which of the limits of `budget` a scan that ran for `elapsed` and went
+/// through `walked` files and `hashed` bytes has reached, if any.
+fn over_budget(
+    budget: &Budget,
+    elapsed: Duration,
+    walked: u64,
+    hashed: u64,
+) -> Option<&'static str> {
+    if budget
+        .max_secs
+        .is_some_and(|secs| elapsed >= Duration::from_secs(secs))
+    {
+        Some("time budget")
+    } else if budget.max_files.is_some_and(|files| walked >= files) {
+        Some("file budget")
+    } else if budget.max_bytes.is_some_and(|bytes| hashed >= bytes) {
+        Some("byte budget")
+    } else {
+        None
+    }
+}
+
+/// Tells whether the scan should stop, checking the budget on the way.
+pub fn stopped() -> bool {
+    if reason().is_some() {
+        return true;
+    }
+    let Some((budget, started)) = BUDGET.get() else {
+        return false;
+    };
+    if let Some(reason) = over_budget(
+        budget,
+        started.elapsed(),
+        progress::walked(),
+        progress::hashed(),
+    ) {
+        stop(reason);
+    }
+    reason().is_some()
+}
+
+pub fn reason() -> Option<&'static str> {
+    *STOP_REASON.lock().unwrap()
+}
+
+/// Error for reads cut short by a stop.
+pub fn stopped_err() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, "scan stopped")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stops_at_the_first_limit_reached() {
+        let budget = Budget {
+            max_secs: Some(60),
+            max_files: Some(100),
+            max_bytes: Some(1 << 20),
+        };
+        let secs = Duration::from_secs;
+        assert_eq!(over_budget(&budget, secs(59), 99, (1 << 20) - 1), None);
+        assert_eq!(over_budget(&budget, secs(60), 0, 0), Some("time budget"));
+        assert_eq!(over_budget(&budget, secs(0), 100, 0), Some("file budget"));
+        assert_eq!(
+            over_budget(&budget, secs(0), 0, 1 << 20),
+            Some("byte budget")
+        );
+        assert_eq!(
+            over_budget(&budget, secs(61), 200, 1 << 30),
+            Some("time budget")
+        );
+    }
+
+    #[test]
+    fn runs_unbounded_without_limits() {
+        let budget = Budget::default();
+        let reason = over_budget(&budget, Duration::from_secs(1 << 20), u64::MAX, u64::MAX);
+        assert_eq!(reason, None);
+    }
+}
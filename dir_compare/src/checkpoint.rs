@@ -0,0 +1,472 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use crate::{
+    archive::is_member, args::Options, hash::Content, progress, spill, subtree::Subtree, File,
+};
+
+const FLUSH_EVERY: Duration = Duration::from_secs(30);
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+/// The checkpoint is a journal, appended a line for every dir walked to its
+/// end and every file hashed. A resumed scan only keeps the paths of the
+/// records and where they start in the journal, and reads them back when
+/// they are needed.
+struct State {
+    output: PathBuf,
+    out: BufWriter<fs::File>,
+    journal: fs::File,
+    /// Dirs that can be put back whole, with everything below them.
+    dirs: HashMap<String, u64>,
+    hashes: HashMap<String, u64>,
+    last_flush: Instant,
+}
+
+/// What a walked dir held, in the order it was walked: its files, archive
+/// members included, and its subdirs.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Child {
+    File(File),
+    Dir(String),
+}
+
+/// The options that decide what a walk records, which a resumed scan must
+/// share.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Walk {
+    dirs: bool,
+    archives: bool,
+    metadata: bool,
+    decompress: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    Walk(Walk),
+    Dir(DirRecord),
+    Hash(HashRecord),
+}
+
+/// A dir walked to its end. It is put back on resume while its mtime, those
+/// of the dirs below it and the sizes and mtimes of its files are unchanged,
+/// since editing a file in place leaves the mtime of its dir alone.
+#[derive(Debug, Serialize, Deserialize)]
+struct DirRecord {
+    path: String,
+    modified: Option<SystemTime>,
+    children: Vec<Child>,
+    subtree: Option<Subtree>,
+}
+
+/// A content hash, valid while the file keeps its size and mtime.
+#[derive(Debug, Serialize, Deserialize)]
+struct HashRecord {
+    path: String,
+    size: u64,
+    modified: Option<SystemTime>,
+    hash: String,
+    len: u64,
+    decompressed: bool,
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Tells whether a recorded file still has the size and mtime it was walked
+/// with. Archive members go by the archive they are in.
+fn file_unchanged(file: &File) -> bool {
+    is_member(&file.path)
+        || fs::symlink_metadata(&file.path).is_ok_and(|metadata| {
+            file.modified.is_some()
+                && metadata.len() == file.size
+                && metadata.modified().ok() == file.modified
+        })
+}
+
+fn read_record(mut journal: &fs::File, offset: u64) -> io::Result<Record> {
+    journal.seek(SeekFrom::Start(offset))?;
+    let mut line = String::new();
+    BufReader::new(journal).read_line(&mut line)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Starts journaling to `output`. With `resume`, its records are picked up
+/// first and `output` goes on from a copy of it, unless they are the same.
+pub fn init(output: PathBuf, resume: Option<&Path>, options: &Options) -> Result<(), String> {
+    let walk = Walk {
+        dirs: options.dirs,
+        archives: options.archives,
+        metadata: options.metadata,
+        decompress: options.decompress,
+    };
+    let write_err = |e: io::Error| {
+        format!(
+            "Couldn't write checkpoint: {}. Error: {}",
+            output.display(),
+            e
+        )
+    };
+    match resume {
+        Some(path) if fs::canonicalize(path).ok() != fs::canonicalize(&output).ok() => {
+            fs::copy(path, &output).map_err(write_err)?;
+        }
+        Some(_) => (),
+        None => fs::write(&output, "").map_err(write_err)?,
+    }
+    let out = OpenOptions::new()
+        .append(true)
+        .open(&output)
+        .map_err(write_err)?;
+    let journal = fs::File::open(&output).map_err(write_err)?;
+
+    let mut dirs: HashMap<String, u64> = HashMap::new();
+    let mut hashes: HashMap<String, u64> = HashMap::new();
+    let mut cut = false;
+    if let Some(path) = resume {
+        let read_err =
+            |e: io::Error| format!("Couldn't read checkpoint: {}. Error: {}", path.display(), e);
+        let mut reader = BufReader::new(&journal);
+        let mut line = String::new();
+        let mut offset = 0;
+        loop {
+            line.clear();
+            let len = reader.read_line(&mut line).map_err(read_err)?;
+            if len == 0 {
+                break;
+            }
+            cut = !line.ends_with('\n');
+            // A line cut short by a crash doesn't parse and is skipped.
+            match serde_json::from_str(&line) {
+                Ok(Record::Walk(recorded)) if recorded != walk => {
+                    return Err(format!(
+                        "Checkpoint {} was written with other --dirs, --archives, \
+                         --metadata or --decompress options",
+                        path.display()
+                    ));
+                }
+                Ok(Record::Dir(record)) => {
+                    let dir = Path::new(&record.path);
+                    let unchanged = record.modified.is_some() && record.modified == modified(dir);
+                    // Subdirs are recorded before the dir they are in.
+                    let below = record.children.iter().all(|child| match child {
+                        Child::Dir(name) => dir
+                            .join(name)
+                            .to_str()
+                            .is_some_and(|path| dirs.contains_key(path)),
+                        Child::File(file) => file_unchanged(file),
+                    });
+                    if unchanged && below {
+                        dirs.insert(record.path, offset);
+                    } else {
+                        dirs.remove(&record.path);
+                    }
+                }
+                Ok(Record::Hash(record)) => {
+                    hashes.insert(record.path, offset);
+                }
+                _ => (),
+            }
+            offset += len as u64;
+        }
+    }
+
+    let mut out = BufWriter::new(out);
+    // Records go on from a line of their own after one cut short.
+    if cut {
+        out.write_all(b"\n").map_err(write_err)?;
+    }
+    let mut state = State {
+        output,
+        out,
+        journal,
+        dirs,
+        hashes,
+        last_flush: Instant::now(),
+    };
+    append(&mut state, &Record::Walk(walk));
+    *STATE.lock().unwrap() = Some(state);
+    Ok(())
+}
+
+/// Tells whether walked dirs are journaled.
+pub fn enabled() -> bool {
+    STATE.lock().unwrap().is_some()
+}
+
+/// Puts back the files and subtrees of `dir` and of everything below it as
+/// an earlier walk recorded them, without reading the dirs again. Returns
+/// the fingerprint `dir` had then, or `None` when it has to be walked.
+pub fn replay(
+    dir: &Path,
+    files: &mut Vec<File>,
+    subtrees: Option<&mut Vec<Subtree>>,
+) -> Option<Option<String>> {
+    let mut state = STATE.lock().unwrap();
+    let state = state.as_mut()?;
+    let offset = *state.dirs.get(dir.to_str()?)?;
+    match replay_at(state, offset, files, subtrees) {
+        Ok(fingerprint) => Some(fingerprint),
+        Err(e) => {
+            eprintln!("Checkpoint err: {}, File {:?}", e, state.output);
+            progress::error();
+            None
+        }
+    }
+}
+
+fn replay_at(
+    state: &State,
+    offset: u64,
+    files: &mut Vec<File>,
+    mut subtrees: Option<&mut Vec<Subtree>>,
+) -> io::Result<Option<String>> {
+    let Record::Dir(record) = read_record(&state.journal, offset)? else {
+        return Err(io::Error::other("not a dir record"));
+    };
+    for child in record.children {
+        match child {
+            Child::File(file) => {
+                if !is_member(&file.path) {
+                    progress::file_walked();
+                }
+                files.push(file);
+                spill::spill(files);
+            }
+            Child::Dir(name) => {
+                let path = Path::new(&record.path).join(name);
+                let offset = path
+                    .to_str()
+                    .and_then(|path| state.dirs.get(path))
+                    .ok_or_else(|| io::Error::other(format!("no record of {:?}", path)))?;
+                replay_at(state, *offset, files, subtrees.as_deref_mut())?;
+            }
+        }
+    }
+    Ok(match (subtrees, record.subtree) {
+        (Some(subtrees), Some(subtree)) => {
+            let fingerprint = subtree.fingerprint.clone();
+            subtrees.push(subtree);
+            Some(fingerprint)
+        }
+        _ => None,
+    })
+}
+
+/// Records that `dir` was walked to its end, with what it held.
+pub fn dir_done(dir: &Path, children: Vec<Child>, subtree: Option<&Subtree>) {
+    let mut state = STATE.lock().unwrap();
+    let Some(state) = state.as_mut() else {
+        return;
+    };
+    let record = DirRecord {
+        path: dir.to_str().expect("Invalid Unicode data").to_string(),
+        modified: modified(dir),
+        children,
+        subtree: subtree.cloned(),
+    };
+    append(state, &Record::Dir(record));
+}
+
+/// Returns the recorded hash of `path` when the file hasn't changed since and
+/// was hashed the same way, on its decompressed content or not.
+pub fn cached_hash(path: &Path, decompressed: bool) -> Option<Content> {
+    let state = STATE.lock().unwrap();
+    let state = state.as_ref()?;
+    let offset = *state.hashes.get(path.to_str()?)?;
+    let Ok(Record::Hash(record)) = read_record(&state.journal, offset) else {
+        return None;
+    };
+    let metadata = fs::metadata(path).ok()?;
+    let unchanged = record.size == metadata.len()
+        && record.modified.is_some()
+        && record.modified == metadata.modified().ok()
+        && record.decompressed == decompressed;
+    unchanged.then_some(Content {
+        hash: record.hash,
+        len: record.len,
+        decompressed: record.decompressed,
+    })
+}
+
+pub fn hashed(path: &Path, content: &Content) {
+    let mut state = STATE.lock().unwrap();
+    let Some(state) = state.as_mut() else {
+        return;
+    };
+    let Ok(metadata) = fs::metadata(path) else {
+        return;
+    };
+    let record = HashRecord {
+        path: path.to_str().expect("Invalid Unicode data").to_string(),
+        size: metadata.len(),
+        modified: metadata.modified().ok(),
+        hash: content.hash.clone(),
+        len: content.len,
+        decompressed: content.decompressed,
+    };
+    append(state, &Record::Hash(record));
+}
+
+/// Appends `record` to the journal, which is flushed every so often so that
+/// a crash loses little of it.
+fn append(state: &mut State, record: &Record) {
+    let result = serde_json::to_writer(&mut state.out, record)
+        .map_err(io::Error::from)
+        .and_then(|_| state.out.write_all(b"\n"));
+    if let Err(e) = result {
+        eprintln!("Checkpoint err: {}, File {:?}", e, state.output);
+    }
+    if state.last_flush.elapsed() >= FLUSH_EVERY {
+        flush(state);
+    }
+}
+
+fn flush(state: &mut State) {
+    if let Err(e) = state.out.flush() {
+        eprintln!("Checkpoint err: {}, File {:?}", e, state.output);
+    }
+    state.last_flush = Instant::now();
+}
+
+pub fn finish() {
+    if let Some(state) = STATE.lock().unwrap().as_mut() {
+        flush(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{args::MatchBy, testing::TempDir};
+
+    /// Scans share the journal state, so they take turns.
+    static SCANS: Mutex<()> = Mutex::new(());
+
+    fn options() -> Options {
+        Options {
+            match_by: MatchBy::Content,
+            dirs: true,
+            ..Default::default()
+        }
+    }
+
+    /// Walks `dir` journaling to `journal`, resuming from it with `resume`.
+    fn scan(dir: &Path, journal: &Path, resume: bool) -> (Vec<File>, Vec<Subtree>) {
+        let options = options();
+        init(journal.to_path_buf(), resume.then_some(journal), &options).unwrap();
+        let mut files = Vec::new();
+        let mut subtrees = Vec::new();
+        crate::walk_dir(dir, &mut files, Some(&mut subtrees), &options).unwrap();
+        finish();
+        *STATE.lock().unwrap() = None;
+        (files, subtrees)
+    }
+
+    fn hash_of<'a>(files: &'a [File], name: &str) -> Option<&'a str> {
+        let file = files.iter().find(|f| f.name == name)?;
+        file.hash.as_deref()
+    }
+
+    fn fingerprint_of<'a>(subtrees: &'a [Subtree], dir: &Path) -> &'a str {
+        let path = dir.to_str().unwrap();
+        &subtrees
+            .iter()
+            .find(|s| s.path == path)
+            .unwrap()
+            .fingerprint
+    }
+
+    #[test]
+    fn resumes_after_an_in_place_edit() {
+        let _turn = SCANS.lock().unwrap_or_else(|e| e.into_inner());
+        let tmp = TempDir::new("checkpoint-edit");
+        let root = tmp.path.join("a");
+        tmp.file("a/sub/x", b"hello");
+        tmp.file("a/y", b"hello");
+        let journal = tmp.path.join("journal");
+
+        let (files, subtrees) = scan(&root, &journal, false);
+        assert_eq!(hash_of(&files, "x"), hash_of(&files, "y"));
+        let before = fingerprint_of(&subtrees, &root).to_string();
+
+        let dir_modified = modified(&root);
+        let y = fs::OpenOptions::new()
+            .write(true)
+            .open(root.join("y"))
+            .unwrap();
+        y.set_len(0).unwrap();
+        (&y).write_all(b"world").unwrap();
+        y.set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(modified(&root), dir_modified);
+
+        let (files, subtrees) = scan(&root, &journal, true);
+        assert!(hash_of(&files, "y").is_some());
+        assert_ne!(hash_of(&files, "x"), hash_of(&files, "y"));
+        assert_ne!(fingerprint_of(&subtrees, &root), before);
+        assert_eq!(subtrees.len(), 2);
+    }
+
+    #[test]
+    fn resumes_after_a_deleted_file() {
+        let _turn = SCANS.lock().unwrap_or_else(|e| e.into_inner());
+        let tmp = TempDir::new("checkpoint-delete");
+        let root = tmp.path.join("a");
+        tmp.file("a/sub/x", b"hello");
+        tmp.file("a/sub/z", b"bye");
+        tmp.file("a/y", b"hello");
+        let journal = tmp.path.join("journal");
+
+        let (files, _) = scan(&root, &journal, false);
+        assert_eq!(files.len(), 3);
+
+        fs::remove_file(root.join("sub/z")).unwrap();
+        let (files, subtrees) = scan(&root, &journal, true);
+        let mut names: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["x", "y"]);
+        let whole = subtrees
+            .iter()
+            .find(|s| Path::new(&s.path) == root)
+            .unwrap();
+        assert_eq!((whole.file_count, whole.size), (2, 10));
+    }
+
+    #[test]
+    fn replays_unchanged_dirs() {
+        let _turn = SCANS.lock().unwrap_or_else(|e| e.into_inner());
+        let tmp = TempDir::new("checkpoint-replay");
+        let root = tmp.path.join("a");
+        tmp.file("a/sub/x", b"hello");
+        let journal = tmp.path.join("journal");
+
+        let (first, subtrees) = scan(&root, &journal, false);
+        let (files, replayed) = scan(&root, &journal, true);
+        assert_eq!(hash_of(&files, "x"), hash_of(&first, "x"));
+        assert_eq!(
+            fingerprint_of(&replayed, &root),
+            fingerprint_of(&subtrees, &root)
+        );
+        let journal = fs::read_to_string(&journal).unwrap();
+        let walks = journal
+            .lines()
+            .filter(|l| l.starts_with("{\"walk\""))
+            .count();
+        let dirs = journal
+            .lines()
+            .filter(|l| l.starts_with("{\"dir\""))
+            .count();
+        assert_eq!((walks, dirs), (2, 2));
+    }
+}
@@ -0,0 +1,274 @@
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::Path,
+};
+
+use crate::{archive::is_member, cancel, hash::feed, progress, Dir, File};
+
+/// Largest average chunk size taken, well below where four times it would
+/// overflow.
+pub const MAX_CHUNK_SIZE: u64 = 1 << 30;
+
+/// Random values the rolling hash adds up, one per byte value.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9e3779b97f4a7c15;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut x = state;
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = x ^ (x >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Cut points of content-defined chunking: a chunk ends where the rolling
+/// hash has its top bits clear, so that an insertion only moves the cuts
+/// near it. Chunks are kept between a quarter and four times the average.
+struct Chunker {
+    average: u64,
+    min: u64,
+    max: u64,
+    mask: u64,
+}
+
+impl Chunker {
+    fn new(average: u64) -> Self {
+        let average = average
+            .checked_next_power_of_two()
+            .filter(|average| *average <= MAX_CHUNK_SIZE)
+            .unwrap_or(MAX_CHUNK_SIZE);
+        Self {
+            average,
+            min: average / 4,
+            max: average * 4,
+            mask: !0u64 << (64 - average.trailing_zeros()),
+        }
+    }
+
+    /// Chunks the file at `path`, handing each chunk's key and length to
+    /// `on_chunk`, and returns the key of the whole file.
+    fn chunk_file(&self, path: &Path, mut on_chunk: impl FnMut(u128, u64)) -> io::Result<u128> {
+        let mut whole = Sha256::new();
+        let mut hasher = Sha256::new();
+        let mut rolling: u64 = 0;
+        let mut len: u64 = 0;
+        feed(fs::File::open(path)?, |bytes| {
+            whole.update(bytes);
+            let mut start = 0;
+            for (idx, byte) in bytes.iter().enumerate() {
+                rolling = (rolling << 1).wrapping_add(GEAR[*byte as usize]);
+                len += 1;
+                if (len >= self.min && rolling & self.mask == 0) || len >= self.max {
+                    hasher.update(&bytes[start..=idx]);
+                    on_chunk(key(hasher.finalize_reset().as_slice()), len);
+                    start = idx + 1;
+                    rolling = 0;
+                    len = 0;
+                }
+            }
+            hasher.update(&bytes[start..]);
+        })?;
+        if len > 0 {
+            on_chunk(key(hasher.finalize().as_slice()), len);
+        }
+        Ok(key(whole.finalize().as_slice()))
+    }
+}
+
+/// The first half of a SHA-256 digest, plenty to tell chunks apart in an
+/// estimate and half the memory.
+fn key(digest: &[u8]) -> u128 {
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    u128::from_be_bytes(bytes)
+}
+
+#[derive(Debug, Serialize)]
+pub struct FileChunks {
+    pub path: String,
+    pub size: u64,
+    pub chunks: usize,
+    /// Bytes of the file in chunks that some other place holds too.
+    pub shared_bytes: u64,
+}
+
+/// How much a chunk-level dedup store would hold of the scanned files.
+#[derive(Debug, Serialize)]
+pub struct ChunkStats {
+    pub average_chunk_size: u64,
+    pub files: usize,
+    pub chunks: u64,
+    pub unique_chunks: usize,
+    pub total_bytes: u64,
+    pub unique_bytes: u64,
+    /// What storing whole files once would save, for comparison.
+    pub whole_file_savings: u64,
+    pub top_files: Vec<FileChunks>,
+}
+
+/// Chunks every file of `dirs` and adds up the bytes of the chunks seen
+/// more than once, in the same file or in others.
+pub fn chunk_dirs(dirs: &[Dir], average: u64, top: usize) -> ChunkStats {
+    let chunker = Chunker::new(average);
+    let files: Vec<&File> = dirs
+        .iter()
+        .flat_map(|d| d.files.iter())
+        .filter(|f| !is_member(&f.path))
+        .collect();
+    progress::set_phase("chunks", Some(files.iter().map(|f| f.size).sum()));
+
+    let mut refs: HashMap<u128, (u64, u32)> = HashMap::new();
+    let mut file_chunks: Vec<(usize, Vec<u128>)> = Vec::new();
+    let mut whole_files: HashSet<u128> = HashSet::new();
+    let mut whole_file_savings = 0;
+    let mut total_bytes = 0;
+    for (idx, file) in files.iter().enumerate() {
+        if cancel::stopped() {
+            break;
+        }
+        let mut keys = Vec::new();
+        let mut bytes = 0;
+        let chunked = chunker.chunk_file(Path::new(&file.path), |key, len| {
+            keys.push((key, len));
+            bytes += len;
+        });
+        match chunked {
+            Ok(whole) => {
+                if bytes > 0 && !whole_files.insert(whole) {
+                    whole_file_savings += bytes;
+                }
+                for (key, len) in keys.iter() {
+                    refs.entry(*key).or_insert((*len, 0)).1 += 1;
+                }
+                total_bytes += bytes;
+                file_chunks.push((idx, keys.into_iter().map(|(key, _)| key).collect()));
+            }
+            Err(e) => {
+                if !cancel::stopped() {
+                    eprintln!("Chunk err: {}, File {:?}", e, file.path);
+                    progress::error();
+                }
+            }
+        }
+    }
+
+    let chunked_files = file_chunks.len();
+    let mut top_files: Vec<FileChunks> = file_chunks
+        .into_iter()
+        .map(|(idx, keys)| FileChunks {
+            path: files[idx].path.clone(),
+            size: files[idx].size,
+            chunks: keys.len(),
+            shared_bytes: keys
+                .iter()
+                .map(|key| refs[key])
+                .filter(|(_, count)| *count > 1)
+                .map(|(len, _)| len)
+                .sum(),
+        })
+        .filter(|f| f.shared_bytes > 0)
+        .collect();
+    top_files.sort_by(|a, b| {
+        (Reverse(a.shared_bytes), &a.path).cmp(&(Reverse(b.shared_bytes), &b.path))
+    });
+    top_files.truncate(top);
+
+    ChunkStats {
+        average_chunk_size: chunker.average,
+        files: chunked_files,
+        chunks: refs.values().map(|(_, count)| *count as u64).sum(),
+        unique_chunks: refs.len(),
+        total_bytes,
+        unique_bytes: refs.values().map(|(len, _)| len).sum(),
+        whole_file_savings,
+        top_files,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunks(chunker: &Chunker, tmp: &TempDir, name: &str, content: &[u8]) -> Vec<(u128, u64)> {
+        let file = tmp.file(name, content);
+        let mut chunks = Vec::new();
+        chunker
+            .chunk_file(Path::new(&file.path), |key, len| chunks.push((key, len)))
+            .unwrap();
+        chunks
+    }
+
+    #[test]
+    fn rounds_the_average_to_a_power_of_two() {
+        let chunker = Chunker::new(3000);
+        assert_eq!(
+            (chunker.average, chunker.min, chunker.max),
+            (4096, 1024, 16384)
+        );
+        assert_eq!(chunker.mask.count_ones(), 12);
+        assert_eq!(Chunker::new(u64::MAX).average, MAX_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn keeps_chunks_within_bounds() {
+        let tmp = TempDir::new("chunks-bounds");
+        let chunker = Chunker::new(1024);
+        let content = noise(200_000, 1);
+        let found = chunks(&chunker, &tmp, "noise", &content);
+        let (last, whole) = found.split_last().unwrap();
+        assert!(whole
+            .iter()
+            .all(|(_, len)| (chunker.min..=chunker.max).contains(len)));
+        assert!(last.1 <= chunker.max);
+        let total: u64 = found.iter().map(|(_, len)| len).sum();
+        assert_eq!(total, content.len() as u64);
+
+        let zeros = chunks(&chunker, &tmp, "zeros", &[0; 10_000]);
+        assert!(zeros[..zeros.len() - 1]
+            .iter()
+            .all(|(_, len)| *len == chunker.max));
+    }
+
+    #[test]
+    fn an_insertion_only_moves_nearby_cuts() {
+        let tmp = TempDir::new("chunks-insert");
+        let chunker = Chunker::new(1024);
+        let content = noise(100_000, 7);
+        let mut edited = content.clone();
+        edited.splice(50_000..50_000, *b"inserted");
+
+        let before: HashSet<u128> = chunks(&chunker, &tmp, "before", &content)
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        let after = chunks(&chunker, &tmp, "after", &edited);
+        let changed = after
+            .iter()
+            .filter(|(key, _)| !before.contains(key))
+            .count();
+        assert!((1..=3).contains(&changed), "{} chunks changed", changed);
+    }
+}
@@ -0,0 +1,318 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::args::{parse_conflict, parse_match, parse_scope, parse_size, Options};
+
+/// Config file next to where dir_compare is run.
+const PROJECT_FILE: &str = "dir_compare.toml";
+
+/// Settings a config file may hold, at the top level or in a profile. Keys
+/// are named like the flags they stand for.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+struct Settings {
+    #[serde(rename = "match")]
+    match_by: Option<String>,
+    ignore_case: Option<bool>,
+    normalize: Option<bool>,
+    stem: Option<bool>,
+    with_size: Option<bool>,
+    scope: Option<String>,
+    archives: Option<bool>,
+    decompress: Option<bool>,
+    dirs: Option<bool>,
+    all_files: Option<bool>,
+    max_distance: Option<u32>,
+    threshold: Option<f64>,
+    metadata: Option<bool>,
+    mtime_tolerance: Option<f64>,
+    chunk_size: Option<Size>,
+    top: Option<usize>,
+    max_time: Option<u64>,
+    max_files: Option<u64>,
+    max_bytes: Option<Size>,
+    max_memory: Option<Size>,
+    no_progress: Option<bool>,
+    progress_json: Option<i32>,
+    checkpoint: Option<PathBuf>,
+    on_duplicate: Option<String>,
+    dry_run: Option<bool>,
+    conflict: Option<String>,
+    skip_existing_content: Option<bool>,
+    sqlite: Option<PathBuf>,
+}
+
+/// A byte count, either as a number or with a K, M, G or T suffix.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+impl Size {
+    fn bytes(&self, key: &str) -> Result<u64, String> {
+        match self {
+            Size::Bytes(bytes) => Ok(*bytes),
+            Size::Text(text) => parse_size(key, Some(text.clone())),
+        }
+    }
+}
+
+impl Settings {
+    fn apply(self, options: &mut Options) -> Result<(), String> {
+        if let Some(value) = self.match_by {
+            options.match_by = parse_match(Some(value))?;
+        }
+        let flags = [
+            (self.ignore_case, &mut options.name_match.ignore_case),
+            (self.normalize, &mut options.name_match.normalize),
+            (self.stem, &mut options.name_match.stem),
+            (self.with_size, &mut options.name_match.with_size),
+            (self.archives, &mut options.archives),
+            (self.decompress, &mut options.decompress),
+            (self.dirs, &mut options.dirs),
+            (self.all_files, &mut options.all_files),
+            (self.metadata, &mut options.metadata),
+            (self.no_progress, &mut options.no_progress),
+            (self.dry_run, &mut options.dry_run),
+            (
+                self.skip_existing_content,
+                &mut options.skip_existing_content,
+            ),
+        ];
+        for (value, flag) in flags {
+            if let Some(value) = value {
+                *flag = value;
+            }
+        }
+        if let Some(value) = self.scope {
+            options.scope = parse_scope(Some(value))?;
+        }
+        if let Some(value) = self.max_distance {
+            options.max_distance = value;
+        }
+        if let Some(value) = self.threshold {
+            options.threshold = value;
+        }
+        if let Some(value) = self.mtime_tolerance {
+            options.mtime_tolerance = value;
+        }
+        if let Some(value) = self.chunk_size {
+            options.chunk_size = value.bytes("chunk-size")?;
+        }
+        if let Some(value) = self.top {
+            options.top = value;
+        }
+        if let Some(value) = self.max_time {
+            options.budget.max_secs = Some(value);
+        }
+        if let Some(value) = self.max_files {
+            options.budget.max_files = Some(value);
+        }
+        if let Some(value) = self.max_bytes {
+            options.budget.max_bytes = Some(value.bytes("max-bytes")?);
+        }
+        if let Some(value) = self.max_memory {
+            options.max_memory = Some(value.bytes("max-memory")?);
+        }
+        if let Some(value) = self.progress_json {
+            options.progress_json = Some(value);
+        }
+        if let Some(value) = self.checkpoint {
+            options.checkpoint = Some(value);
+        }
+        if let Some(value) = self.on_duplicate {
+            options.on_duplicate = Some(value);
+        }
+        if let Some(value) = self.conflict {
+            options.conflict = parse_conflict(Some(value))?;
+        }
+        if let Some(value) = self.sqlite {
+            options.sqlite = Some(value);
+        }
+        Ok(())
+    }
+}
+
+/// A config file: top-level settings, plus named profiles under `profiles`.
+struct ConfigFile {
+    path: PathBuf,
+    settings: Settings,
+    profiles: HashMap<String, Settings>,
+}
+
+fn user_file() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config_dir.join("dir_compare").join("config.toml"))
+}
+
+fn parse_settings(table: toml::Table, path: &Path, section: &str) -> Result<Settings, String> {
+    toml::Value::Table(table).try_into().map_err(|e| {
+        format!(
+            "Invalid config: {} [{}]. Error: {}",
+            path.display(),
+            section,
+            e
+        )
+    })
+}
+
+fn read_file(path: PathBuf) -> Result<ConfigFile, String> {
+    let text = fs::read_to_string(&path)
+        .map_err(|e| format!("Couldn't read config: {}. Error: {}", path.display(), e))?;
+    let mut table: toml::Table = text
+        .parse()
+        .map_err(|e| format!("Invalid config: {}. Error: {}", path.display(), e))?;
+    let mut profiles = HashMap::new();
+    match table.remove("profiles") {
+        Some(toml::Value::Table(tables)) => {
+            for (name, profile) in tables {
+                let toml::Value::Table(profile) = profile else {
+                    return Err(format!(
+                        "Invalid config: {}. Error: profile {} must be a table",
+                        path.display(),
+                        name
+                    ));
+                };
+                let settings = parse_settings(profile, &path, &name)?;
+                profiles.insert(name, settings);
+            }
+        }
+        Some(_) => {
+            return Err(format!(
+                "Invalid config: {}. Error: profiles must be a table",
+                path.display()
+            ))
+        }
+        None => (),
+    }
+    let settings = parse_settings(table, &path, "top level")?;
+    Ok(ConfigFile {
+        path,
+        settings,
+        profiles,
+    })
+}
+
+/// Builds the options the config files set: the top-level settings of the
+/// user-level file, then of the project one, then the settings of `profile`
+/// from each in the same order, so that a picked profile wins over any
+/// top-level setting. Flags given on the command line are applied on top by
+/// the caller.
+pub fn load(profile: Option<&str>) -> Result<Options, String> {
+    let paths = user_file().into_iter().chain([PathBuf::from(PROJECT_FILE)]);
+    load_files(paths, profile)
+}
+
+/// Loads the config files at `paths` that exist, in order.
+fn load_files(
+    paths: impl IntoIterator<Item = PathBuf>,
+    profile: Option<&str>,
+) -> Result<Options, String> {
+    let mut files: Vec<ConfigFile> = paths
+        .into_iter()
+        .filter(|path| path.is_file())
+        .map(read_file)
+        .collect::<Result<_, _>>()?;
+
+    let mut options = Options::default();
+    let mut profiles = Vec::new();
+    for file in files.iter_mut() {
+        options.config_files.push(file.path.clone());
+        if let Some(settings) = profile.and_then(|name| file.profiles.remove(name)) {
+            profiles.push(settings);
+        }
+    }
+    if let Some(name) = profile {
+        if profiles.is_empty() {
+            return Err(format!("Unknown profile: {}", name));
+        }
+        options.profile = Some(name.to_string());
+    }
+
+    for file in files {
+        file.settings.apply(&mut options)?;
+    }
+    for settings in profiles {
+        settings.apply(&mut options)?;
+    }
+    Ok(options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{args::MatchBy, testing::TempDir};
+
+    const USER: &str = r#"
+match = "content"
+top = 5
+chunk-size = "8K"
+
+[profiles.fast]
+top = 1
+decompress = true
+"#;
+
+    const PROJECT: &str = r#"
+top = 20
+max-memory = 4096
+
+[profiles.fast]
+chunk-size = 2048
+"#;
+
+    fn load_with(profile: Option<&str>) -> Result<Options, String> {
+        let tmp = TempDir::new(&format!("config-{}", profile.unwrap_or("none")));
+        let user = tmp.path.join("user.toml");
+        let project = tmp.path.join("project.toml");
+        fs::write(&user, USER).unwrap();
+        fs::write(&project, PROJECT).unwrap();
+        load_files([user, tmp.path.join("missing.toml"), project], profile)
+    }
+
+    #[test]
+    fn project_file_wins_over_user_file() {
+        let options = load_with(None).unwrap();
+        assert_eq!(options.match_by, MatchBy::Content);
+        assert_eq!(options.top, 20);
+        assert_eq!(options.chunk_size, 8 * 1024);
+        assert_eq!(options.max_memory, Some(4096));
+        assert!(!options.decompress);
+        assert_eq!(options.config_files.len(), 2);
+    }
+
+    #[test]
+    fn profile_wins_over_top_level_settings() {
+        let options = load_with(Some("fast")).unwrap();
+        assert_eq!(options.top, 1);
+        assert!(options.decompress);
+        assert_eq!(options.chunk_size, 2048);
+        assert_eq!(options.profile.as_deref(), Some("fast"));
+    }
+
+    #[test]
+    fn unknown_profile_is_an_error() {
+        assert_eq!(
+            load_with(Some("slow")).unwrap_err(),
+            "Unknown profile: slow"
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_an_error() {
+        let tmp = TempDir::new("config-unknown");
+        let path = tmp.path.join("config.toml");
+        fs::write(&path, "colour = true\n").unwrap();
+        assert!(load_files([path], None)
+            .unwrap_err()
+            .starts_with("Invalid config"));
+    }
+}
@@ -0,0 +1,400 @@
+use rusqlite::{params, Connection};
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    args::{Options, Scope},
+    cancel,
+    progress::{self, human_bytes},
+    report::is_partial,
+    stats::{Member, RootStats},
+    Dir, Duplicate,
+};
+
+const SCHEMA: &str = "
+CREATE TABLE scan (
+    created INTEGER NOT NULL,
+    options TEXT NOT NULL,
+    partial INTEGER NOT NULL,
+    stop_reason TEXT,
+    errors INTEGER NOT NULL
+);
+CREATE TABLE roots (
+    id INTEGER PRIMARY KEY,
+    path TEXT NOT NULL,
+    files INTEGER NOT NULL,
+    bytes INTEGER NOT NULL
+);
+CREATE TABLE groups (
+    id INTEGER PRIMARY KEY,
+    file_name TEXT NOT NULL,
+    scope TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    copies INTEGER NOT NULL
+);
+CREATE TABLE files (
+    id INTEGER PRIMARY KEY,
+    root INTEGER NOT NULL REFERENCES roots(id),
+    path TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    group_id INTEGER REFERENCES groups(id),
+    position INTEGER
+);
+CREATE INDEX groups_size ON groups(size);
+CREATE INDEX files_group ON files(group_id);
+CREATE INDEX files_path ON files(path);
+";
+
+/// Filters of the `query` command. A group matches when one of its files
+/// passes all of them.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub under: Option<String>,
+    pub min_size: Option<u64>,
+    pub ext: Option<String>,
+    pub root: Option<String>,
+}
+
+/// The files of a scan and their duplicate groups, kept in a SQLite file so
+/// that they can be queried without scanning again. The whole scan is
+/// written in one transaction, committed by `finish`.
+pub struct Index {
+    conn: Connection,
+}
+
+impl Index {
+    /// Starts an index at `path`, replacing the file there, which the
+    /// arguments only allow with `--force`.
+    pub fn create(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let conn = Connection::open(path)?;
+        conn.execute_batch(SCHEMA)?;
+        // Roots are only known once the walk is over, so they go in last and
+        // references to them are checked on commit.
+        conn.execute_batch("PRAGMA foreign_keys = ON; BEGIN; PRAGMA defer_foreign_keys = ON;")?;
+        Ok(Self { conn })
+    }
+
+    /// Adds a scanned file, in no group until `add_group` puts it in one.
+    pub fn add_file(&mut self, file: &Member) -> rusqlite::Result<()> {
+        self.conn
+            .prepare_cached("INSERT INTO files (root, path, size) VALUES (?1, ?2, ?3)")?
+            .execute(params![file.root, file.path, file.size])?;
+        Ok(())
+    }
+
+    /// Adds a group of files added before, its first member being the copy
+    /// to keep. Members are taken one at a time, so that a group needn't be
+    /// held whole.
+    pub fn add_group(
+        &mut self,
+        file_name: &str,
+        scope: Scope,
+        members: impl IntoIterator<Item = Member>,
+    ) -> rusqlite::Result<()> {
+        let mut members = members.into_iter().peekable();
+        let Some(first) = members.peek() else {
+            return Ok(());
+        };
+        let scope = match scope {
+            Scope::IntraRoot => "intra_root",
+            Scope::CrossRoot => "cross_root",
+        };
+        self.conn
+            .prepare_cached(
+                "INSERT INTO groups (file_name, scope, size, copies) VALUES (?1, ?2, ?3, 0)",
+            )?
+            .execute(params![file_name, scope, first.size])?;
+        let group_id = self.conn.last_insert_rowid();
+        let mut update = self.conn.prepare_cached(
+            "UPDATE files SET group_id = ?1, position = ?2 WHERE root = ?3 AND path = ?4",
+        )?;
+        let mut copies = 0;
+        for (position, member) in members.enumerate() {
+            update.execute(params![group_id, position, member.root, member.path])?;
+            copies = position;
+        }
+        self.conn
+            .prepare_cached("UPDATE groups SET copies = ?1 WHERE id = ?2")?
+            .execute(params![copies, group_id])?;
+        Ok(())
+    }
+
+    /// Stores the roots and the scan metadata, then commits.
+    pub fn finish(
+        self,
+        roots: &[RootStats],
+        options: &Options,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for (id, root) in roots.iter().enumerate() {
+            self.conn.execute(
+                "INSERT INTO roots (id, path, files, bytes) VALUES (?1, ?2, ?3, ?4)",
+                params![id, root.path, root.files, root.bytes],
+            )?;
+        }
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        let options = serde_json::to_string(options)?;
+        self.conn.execute(
+            "INSERT INTO scan (created, options, partial, stop_reason, errors)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                created,
+                options,
+                is_partial(),
+                cancel::reason(),
+                progress::errors()
+            ],
+        )?;
+        self.conn.execute_batch("COMMIT")?;
+        Ok(())
+    }
+}
+
+/// Writes the files of `dirs` and the duplicate groups found in memory to a
+/// new index at `path`.
+pub fn write_duplicates(
+    path: &Path,
+    dirs: &[Dir],
+    duplicates: &[Duplicate],
+    roots: &[RootStats],
+    options: &Options,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let files: HashMap<&str, (usize, u64)> = dirs
+        .iter()
+        .enumerate()
+        .flat_map(|(root, d)| {
+            d.files
+                .iter()
+                .map(move |f| (f.path.as_str(), (root, f.size)))
+        })
+        .collect();
+    let mut index = Index::create(path)?;
+    for (root, dir) in dirs.iter().enumerate() {
+        for file in dir.files.iter() {
+            index.add_file(&Member {
+                root,
+                path: file.path.clone(),
+                size: file.size,
+            })?;
+        }
+    }
+    for dup in duplicates {
+        let members = std::iter::once(dup.first_dir_match)
+            .chain(dup.second_dir_match.iter().map(String::as_str))
+            .filter_map(|path| {
+                let (root, size) = files.get(path)?;
+                Some(Member {
+                    root: *root,
+                    path: path.to_string(),
+                    size: *size,
+                })
+            });
+        index.add_group(dup.file_name, dup.scope, members)?;
+    }
+    index.finish(roots, options)
+}
+
+/// Prints the groups of the index at `path` that pass `filter`, largest
+/// first, and tells whether there were any.
+pub fn query(path: &Path, filter: &Filter) -> Result<bool, Box<dyn std::error::Error>> {
+    let conn = Connection::open_with_flags(path, rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY)
+        .map_err(|e| format!("Couldn't open index: {}. Error: {}", path.display(), e))?;
+    let (partial, stop_reason): (bool, Option<String>) = conn
+        .query_row("SELECT partial, stop_reason FROM scan", [], |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .map_err(|e| format!("Invalid index: {}. Error: {}", path.display(), e))?;
+    if partial {
+        eprintln!(
+            "Index {} is from a partial scan{}",
+            path.display(),
+            stop_reason
+                .map(|reason| format!(" ({})", reason))
+                .unwrap_or_default()
+        );
+    }
+
+    let under = filter
+        .under
+        .as_ref()
+        .map(|under| under.trim_end_matches('/').to_string());
+    let ext = filter
+        .ext
+        .as_ref()
+        .map(|ext| format!(".{}", ext.trim_start_matches('.').to_lowercase()));
+    let mut groups = conn.prepare(
+        "SELECT g.id, g.size, g.copies FROM groups g
+         WHERE g.size >= ?1 AND EXISTS (
+             SELECT 1 FROM files f JOIN roots r ON r.id = f.root
+             WHERE f.group_id = g.id
+               AND (?2 IS NULL OR f.path = ?2 OR substr(f.path, 1, length(?2) + 1) = ?2 || '/')
+               AND (?3 IS NULL OR substr(lower(f.path), -length(?3)) = ?3)
+               AND (?4 IS NULL OR r.path = ?4))
+         ORDER BY g.size * g.copies DESC, g.id",
+    )?;
+    let mut files = conn.prepare("SELECT path FROM files WHERE group_id = ?1 ORDER BY position")?;
+
+    let rows = groups.query_map(
+        params![filter.min_size.unwrap_or(0), under, ext, filter.root],
+        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, u64>(1)?, row.get(2)?)),
+    )?;
+    let mut count = 0;
+    let mut copies = 0;
+    let mut bytes = 0;
+    for row in rows {
+        let (id, size, group_copies): (i64, u64, u64) = row?;
+        let paths: Vec<String> = files
+            .query_map([id], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        println!("{} in {} copies:", human_bytes(size), group_copies);
+        for path in paths {
+            println!("  {}", path);
+        }
+        count += 1;
+        copies += group_copies;
+        bytes += size * group_copies;
+    }
+    println!("Groups: {:?}", count);
+    println!("Redundant copies: {:?}", copies);
+    println!("Redundant bytes: {}", human_bytes(bytes));
+    Ok(count > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing::TempDir, File};
+
+    fn roots(dirs: &[Dir]) -> Vec<RootStats> {
+        dirs.iter()
+            .map(|d| RootStats {
+                path: d.name.clone(),
+                files: d.files.len() as u64,
+                bytes: d.files.iter().map(|f| f.size).sum(),
+            })
+            .collect()
+    }
+
+    fn duplicate<'a>(first: &'a File, copies: &[&File]) -> Duplicate<'a> {
+        Duplicate {
+            file_name: &first.name,
+            first_dir_match: &first.path,
+            second_dir_match: copies.iter().map(|c| c.path.clone()).collect(),
+            scope: Scope::CrossRoot,
+            roots: Vec::new(),
+            decompressed: Vec::new(),
+            metadata_mismatches: Vec::new(),
+        }
+    }
+
+    fn rows(path: &Path, sql: &str) -> Vec<(String, Option<i64>)> {
+        let conn = Connection::open(path).unwrap();
+        let mut stmt = conn.prepare(sql).unwrap();
+        stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn writes_files_groups_and_roots() {
+        let tmp = TempDir::new("db-write");
+        let dirs = vec![
+            tmp.dir("left", &[("a.txt", b"same"), ("b.txt", b"other")]),
+            tmp.dir("right", &[("sub/a.txt", b"same")]),
+        ];
+        let duplicates = vec![duplicate(&dirs[0].files[0], &[&dirs[1].files[0]])];
+        let path = tmp.path.join("index.db");
+        write_duplicates(
+            &path,
+            &dirs,
+            &duplicates,
+            &roots(&dirs),
+            &Options::default(),
+        )
+        .unwrap();
+
+        let rel = |p: &str| {
+            p.strip_prefix(&format!("{}/", tmp.path.display()))
+                .unwrap()
+                .to_string()
+        };
+        let files: Vec<(String, Option<i64>)> =
+            rows(&path, "SELECT path, position FROM files ORDER BY id")
+                .into_iter()
+                .map(|(p, position)| (rel(&p), position))
+                .collect();
+        assert_eq!(
+            files,
+            [
+                ("left/a.txt".to_string(), Some(0)),
+                ("left/b.txt".to_string(), None),
+                ("right/sub/a.txt".to_string(), Some(1)),
+            ]
+        );
+        assert_eq!(
+            rows(&path, "SELECT scope, copies FROM groups"),
+            [("cross_root".to_string(), Some(1))]
+        );
+        assert_eq!(
+            rows(&path, "SELECT path, files FROM roots ORDER BY id"),
+            [
+                (dirs[0].name.clone(), Some(2)),
+                (dirs[1].name.clone(), Some(1))
+            ]
+        );
+
+        assert!(query(&path, &Filter::default()).unwrap());
+        let under = |under: &str| Filter {
+            under: Some(format!("{}/{}", tmp.path.display(), under)),
+            ..Filter::default()
+        };
+        assert!(query(&path, &under("right/sub/")).unwrap());
+        assert!(!query(&path, &under("right/su")).unwrap());
+        let ext = |ext: &str| Filter {
+            ext: Some(ext.to_string()),
+            ..Filter::default()
+        };
+        assert!(query(&path, &ext(".TXT")).unwrap());
+        assert!(!query(&path, &ext("csv")).unwrap());
+        let min_size = Filter {
+            min_size: Some(5),
+            ..Filter::default()
+        };
+        assert!(!query(&path, &min_size).unwrap());
+    }
+
+    #[test]
+    fn replaces_an_existing_index() {
+        let tmp = TempDir::new("db-replace");
+        let dirs = vec![tmp.dir("root", &[("a", b"x"), ("b", b"x")])];
+        let path = tmp.path.join("index.db");
+        fs::write(&path, b"not a database").unwrap();
+
+        let duplicates = vec![duplicate(&dirs[0].files[0], &[&dirs[0].files[1]])];
+        write_duplicates(
+            &path,
+            &dirs,
+            &duplicates,
+            &roots(&dirs),
+            &Options::default(),
+        )
+        .unwrap();
+        write_duplicates(&path, &dirs, &[], &roots(&dirs), &Options::default()).unwrap();
+
+        let count = |sql| rows(&path, sql)[0].1;
+        assert_eq!(count("SELECT 'files', count(*) FROM files"), Some(2));
+        assert_eq!(count("SELECT 'groups', count(*) FROM groups"), Some(0));
+        assert_eq!(count("SELECT 'scans', count(*) FROM scan"), Some(1));
+        assert!(!query(&path, &Filter::default()).unwrap());
+    }
+}
@@ -0,0 +1,135 @@
+use flate2::read::MultiGzDecoder;
+use std::{
+    fs,
+    io::{self, BufReader, Read},
+    path::Path,
+};
+
+/// Single-stream compression formats recognised by file extension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+}
+
+pub fn codec_of(path: &Path) -> Option<Codec> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    match ext.as_str() {
+        "gz" | "tgz" => Some(Codec::Gzip),
+        "zst" => Some(Codec::Zstd),
+        "xz" | "txz" => Some(Codec::Xz),
+        "bz2" | "tbz2" => Some(Codec::Bzip2),
+        _ => None,
+    }
+}
+
+/// Opens `path` and decodes it with `codec` while it's being read.
+pub fn open(path: &Path, codec: Codec) -> io::Result<Box<dyn Read>> {
+    let file = BufReader::new(fs::File::open(path)?);
+    Ok(match codec {
+        Codec::Gzip => Box::new(MultiGzDecoder::new(file)),
+        Codec::Zstd => Box::new(zstd::Decoder::with_buffer(file)?),
+        Codec::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(file)),
+        Codec::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(file)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{hash::hash_content, testing::TempDir};
+    use std::io::Write;
+
+    const TEXT: &[u8] = b"id,value\n1,one\n2,two\n3,three\n";
+
+    fn compress(codec: Codec, bytes: &[u8]) -> Vec<u8> {
+        fn finish<W: Write>(mut writer: W, bytes: &[u8]) -> W {
+            writer.write_all(bytes).unwrap();
+            writer
+        }
+        match codec {
+            Codec::Gzip => {
+                let encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+                finish(encoder, bytes).finish().unwrap()
+            }
+            Codec::Zstd => zstd::encode_all(bytes, 1).unwrap(),
+            Codec::Xz => finish(xz2::write::XzEncoder::new(Vec::new(), 1), bytes)
+                .finish()
+                .unwrap(),
+            Codec::Bzip2 => {
+                let encoder = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::fast());
+                finish(encoder, bytes).finish().unwrap()
+            }
+        }
+    }
+
+    #[test]
+    fn recognises_codecs_by_extension() {
+        let codec = |name: &str| codec_of(Path::new(name));
+        assert_eq!(codec("data.csv.gz"), Some(Codec::Gzip));
+        assert_eq!(codec("data.TGZ"), Some(Codec::Gzip));
+        assert_eq!(codec("data.zst"), Some(Codec::Zstd));
+        assert_eq!(codec("data.txz"), Some(Codec::Xz));
+        assert_eq!(codec("data.bz2"), Some(Codec::Bzip2));
+        assert_eq!(codec("data.csv"), None);
+        assert_eq!(codec("gz"), None);
+    }
+
+    #[test]
+    fn decompresses_each_codec() {
+        let tmp = TempDir::new("decompress-codecs");
+        let plain = tmp.file("data.csv", TEXT);
+        let plain = hash_content(Path::new(&plain.path), true).unwrap();
+        for (name, codec) in [
+            ("data.csv.gz", Codec::Gzip),
+            ("data.csv.zst", Codec::Zstd),
+            ("data.csv.xz", Codec::Xz),
+            ("data.csv.bz2", Codec::Bzip2),
+        ] {
+            let file = tmp.file(name, &compress(codec, TEXT));
+            let path = Path::new(&file.path);
+            let mut bytes = Vec::new();
+            open(path, codec).unwrap().read_to_end(&mut bytes).unwrap();
+            assert_eq!(bytes, TEXT, "{}", name);
+
+            let content = hash_content(path, true).unwrap();
+            assert!(content.decompressed, "{}", name);
+            assert_eq!((content.hash, content.len), (plain.hash.clone(), plain.len));
+            assert!(!hash_content(path, false).unwrap().decompressed);
+        }
+    }
+
+    #[test]
+    fn reads_every_stream_of_a_file() {
+        let tmp = TempDir::new("decompress-streams");
+        let (head, tail) = TEXT.split_at(10);
+        for (name, codec) in [
+            ("data.gz", Codec::Gzip),
+            ("data.bz2", Codec::Bzip2),
+            ("data.xz", Codec::Xz),
+        ] {
+            let file = tmp.file(
+                name,
+                &[compress(codec, head), compress(codec, tail)].concat(),
+            );
+            let mut bytes = Vec::new();
+            open(Path::new(&file.path), codec)
+                .unwrap()
+                .read_to_end(&mut bytes)
+                .unwrap();
+            assert_eq!(bytes, TEXT, "{}", name);
+        }
+    }
+
+    #[test]
+    fn hashes_undecodable_files_raw() {
+        let tmp = TempDir::new("decompress-raw");
+        let file = tmp.file("fake.gz", TEXT);
+        let content = hash_content(Path::new(&file.path), true).unwrap();
+        assert!(!content.decompressed);
+        assert_eq!(content.len, TEXT.len() as u64);
+    }
+}
@@ -0,0 +1,388 @@
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    path::Path,
+    time::SystemTime,
+};
+
+use crate::{
+    cancel, decompress::codec_of, hash::hash_content, metadata::Mismatch, progress, Dir, File,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    OnlyLeft,
+    OnlyRight,
+    Identical,
+    Changed,
+    Moved,
+    Renamed,
+    /// Content wasn't compared before the scan stopped.
+    Unchecked,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiffEntry<'a> {
+    pub path: String,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub old_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub left: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub right: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub size_delta: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtime_delta: Option<i64>,
+    /// Content was compared after decompressing.
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub decompressed: bool,
+    /// Metadata of the right file that differs from the left one, with
+    /// `--metadata`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub metadata_mismatches: Vec<Mismatch<'a>>,
+}
+
+fn relative_files(dir: &Dir) -> BTreeMap<String, &File> {
+    dir.files
+        .iter()
+        .map(|file| {
+            let rel = Path::new(&file.path)
+                .strip_prefix(&dir.name)
+                .unwrap_or(Path::new(&file.path));
+            (rel.display().to_string(), file)
+        })
+        .collect()
+}
+
+fn secs_between(from: Option<SystemTime>, to: Option<SystemTime>) -> Option<i64> {
+    let (from, to) = (from?, to?);
+    match to.duration_since(from) {
+        Ok(d) => Some(d.as_secs() as i64),
+        Err(e) => Some(-(e.duration().as_secs() as i64)),
+    }
+}
+
+fn content_hash(file: &File, decompress: bool) -> Option<String> {
+    if file.hash.is_some() {
+        return file.hash.clone();
+    }
+    match hash_content(Path::new(&file.path), decompress) {
+        Ok(content) => Some(content.hash),
+        Err(e) => {
+            if !cancel::stopped() {
+                eprintln!("Hash err: {}, File {:?}", e, file.path);
+                progress::error();
+            }
+            None
+        }
+    }
+}
+
+fn decompressed(file: &File, decompress: bool) -> bool {
+    decompress && codec_of(Path::new(&file.path)).is_some()
+}
+
+/// The size of what is compared of `file`, its decompressed content with
+/// `decompress`, along with the hash when it took hashing to learn it. The
+/// size is `None` when the hash is already known, as for manifest entries
+/// and archive members, which can't be read again from their path.
+fn compared_size(file: &File, decompress: bool) -> Option<(Option<u64>, Option<String>)> {
+    if !decompressed(file, decompress) {
+        return Some((Some(file.size), None));
+    }
+    if file.hash.is_some() {
+        return Some((None, file.hash.clone()));
+    }
+    match hash_content(Path::new(&file.path), true) {
+        Ok(content) => Some((Some(content.len), Some(content.hash))),
+        Err(e) => {
+            if !cancel::stopped() {
+                eprintln!("Hash err: {}, File {:?}", e, file.path);
+                progress::error();
+            }
+            None
+        }
+    }
+}
+
+/// Compares the content of two files, `None` when either can't be read.
+fn same_content(left: &File, right: &File, decompress: bool) -> Option<bool> {
+    if left.size != right.size && !decompressed(left, decompress) {
+        return Some(false);
+    }
+    Some(content_hash(left, decompress)? == content_hash(right, decompress)?)
+}
+
+/// Pairs left-only and right-only files with equal content, compared after
+/// decompressing with `decompress`. Empty files are left alone, since any two
+/// of them would match.
+fn detect_moves(
+    entries: &mut Vec<DiffEntry>,
+    left_files: &BTreeMap<String, &File>,
+    right_files: &BTreeMap<String, &File>,
+    decompress: bool,
+) {
+    type Sized = Vec<(usize, Option<u64>, Option<String>)>;
+    let sized = |status: Status, files: &BTreeMap<String, &File>| -> Sized {
+        entries
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.status == status && files[&e.path].size > 0)
+            .filter_map(|(idx, e)| {
+                let (size, hash) = compared_size(files[&e.path], decompress)?;
+                Some((idx, size, hash))
+            })
+            .filter(|(_, size, _)| *size != Some(0))
+            .collect()
+    };
+    // Files of unknown size may match any size on the other side.
+    let sizes = |files: &Sized| -> Option<HashSet<u64>> {
+        files.iter().map(|(_, size, _)| *size).collect()
+    };
+    let left_only = sized(Status::OnlyLeft, left_files);
+    let right_only = sized(Status::OnlyRight, right_files);
+    let right_sizes = sizes(&right_only);
+    let left_sizes = sizes(&left_only);
+    let candidate =
+        |size: Option<u64>, other_sizes: &Option<HashSet<u64>>| match (size, other_sizes) {
+            (Some(size), Some(other_sizes)) => other_sizes.contains(&size),
+            _ => true,
+        };
+
+    let mut left_by_hash: HashMap<String, Vec<usize>> = HashMap::new();
+    for (idx, size, hash) in left_only {
+        if candidate(size, &right_sizes) {
+            let hash = hash.or_else(|| content_hash(left_files[&entries[idx].path], decompress));
+            if let Some(hash) = hash {
+                left_by_hash.entry(hash).or_default().push(idx);
+            }
+        }
+    }
+
+    let mut moved_from: HashSet<usize> = HashSet::new();
+    for (idx, size, hash) in right_only {
+        if !candidate(size, &left_sizes) {
+            continue;
+        }
+        let hash = hash.or_else(|| content_hash(right_files[&entries[idx].path], decompress));
+        let Some(hash) = hash else {
+            continue;
+        };
+        let Some(candidates) = left_by_hash.get_mut(&hash) else {
+            continue;
+        };
+        if candidates.is_empty() {
+            continue;
+        }
+        let name = Path::new(&entries[idx].path).file_name();
+        let pos = candidates
+            .iter()
+            .position(|&l| Path::new(&entries[l].path).file_name() == name)
+            .unwrap_or(0);
+        let left_idx = candidates.remove(pos);
+
+        let old_path = entries[left_idx].path.clone();
+        let left = entries[left_idx].left;
+        let same_parent = Path::new(&old_path).parent() == Path::new(&entries[idx].path).parent();
+        let (l, r) = (left_files[&old_path], right_files[&entries[idx].path]);
+        let mtime_delta = secs_between(l.modified, r.modified);
+        let entry = &mut entries[idx];
+        entry.status = if same_parent {
+            Status::Renamed
+        } else {
+            Status::Moved
+        };
+        entry.old_path = Some(old_path);
+        entry.left = left;
+        entry.size_delta = Some(0);
+        entry.mtime_delta = mtime_delta;
+        entry.decompressed = decompressed(l, decompress) || decompressed(r, decompress);
+        moved_from.insert(left_idx);
+    }
+
+    let mut idx = 0;
+    entries.retain(|_| {
+        idx += 1;
+        !moved_from.contains(&(idx - 1))
+    });
+}
+
+/// Pairs files of both dirs by their path relative to the dir root, then
+/// matches the leftovers by content to find moved and renamed files.
+pub fn diff_dirs<'a>(left: &'a Dir, right: &'a Dir, decompress: bool) -> Vec<DiffEntry<'a>> {
+    let left_files = relative_files(left);
+    let right_files = relative_files(right);
+    let paths: BTreeSet<&String> = left_files.keys().chain(right_files.keys()).collect();
+
+    let mut entries = paths
+        .into_iter()
+        .map(|path| {
+            let l = left_files.get(path).copied();
+            let r = right_files.get(path).copied();
+            let status = match (l, r) {
+                (Some(_), None) => Status::OnlyLeft,
+                (None, Some(_)) => Status::OnlyRight,
+                (Some(l), Some(r)) => match same_content(l, r, decompress) {
+                    Some(true) => Status::Identical,
+                    None if cancel::stopped() => Status::Unchecked,
+                    _ => Status::Changed,
+                },
+                (None, None) => unreachable!("Path comes from one of the dirs"),
+            };
+            let (size_delta, mtime_delta) = match (l, r) {
+                (Some(l), Some(r)) => (
+                    Some(r.size as i64 - l.size as i64),
+                    secs_between(l.modified, r.modified),
+                ),
+                _ => (None, None),
+            };
+            DiffEntry {
+                path: path.clone(),
+                status,
+                old_path: None,
+                left: l.map(|f| f.path.as_str()),
+                right: r.map(|f| f.path.as_str()),
+                size_delta,
+                mtime_delta,
+                decompressed: l.zip(r).is_some_and(|(l, _)| decompressed(l, decompress)),
+                metadata_mismatches: Vec::new(),
+            }
+        })
+        .collect();
+
+    detect_moves(&mut entries, &left_files, &right_files, decompress);
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::io::Write;
+
+    fn statuses(entries: &[DiffEntry]) -> Vec<(String, Status, Option<String>)> {
+        entries
+            .iter()
+            .map(|e| (e.path.clone(), e.status, e.old_path.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn classifies_paired_and_unpaired_files() {
+        let tmp = TempDir::new("diff-classify");
+        let left = tmp.dir(
+            "left",
+            &[("same", b"same"), ("edited", b"old"), ("gone", b"gone")],
+        );
+        let right = tmp.dir(
+            "right",
+            &[("same", b"same"), ("edited", b"new"), ("added", b"added")],
+        );
+        assert_eq!(
+            statuses(&diff_dirs(&left, &right, false)),
+            vec![
+                ("added".to_string(), Status::OnlyRight, None),
+                ("edited".to_string(), Status::Changed, None),
+                ("gone".to_string(), Status::OnlyLeft, None),
+                ("same".to_string(), Status::Identical, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn tells_renames_from_moves() {
+        let tmp = TempDir::new("diff-moves");
+        let left = tmp.dir(
+            "left",
+            &[("a/notes", b"notes"), ("a/photo", b"photo"), ("empty", b"")],
+        );
+        let right = tmp.dir(
+            "right",
+            &[
+                ("a/notes-2", b"notes"),
+                ("b/photo", b"photo"),
+                ("void", b""),
+            ],
+        );
+        assert_eq!(
+            statuses(&diff_dirs(&left, &right, false)),
+            vec![
+                (
+                    "a/notes-2".to_string(),
+                    Status::Renamed,
+                    Some("a/notes".to_string())
+                ),
+                (
+                    "b/photo".to_string(),
+                    Status::Moved,
+                    Some("a/photo".to_string())
+                ),
+                ("empty".to_string(), Status::OnlyLeft, None),
+                ("void".to_string(), Status::OnlyRight, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn prefers_a_candidate_with_the_same_name() {
+        let tmp = TempDir::new("diff-names");
+        let left = tmp.dir("left", &[("x/a", b"copy"), ("y/b", b"copy")]);
+        let right = tmp.dir("right", &[("z/b", b"copy")]);
+        let entries = diff_dirs(&left, &right, false);
+        let moved = entries.iter().find(|e| e.path == "z/b").unwrap();
+        assert_eq!(moved.status, Status::Moved);
+        assert_eq!(moved.old_path.as_deref(), Some("y/b"));
+    }
+
+    #[test]
+    fn finds_moves_into_compressed_copies() {
+        let tmp = TempDir::new("diff-decompress");
+        let content = b"some text that gzip makes a different size".repeat(20);
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&content).unwrap();
+        let gz = gz.finish().unwrap();
+        let left = tmp.dir("left", &[("data.txt", &content)]);
+        let right = tmp.dir("right", &[("old/data.txt.gz", &gz)]);
+
+        let entries = diff_dirs(&left, &right, true);
+        assert_eq!(
+            statuses(&entries),
+            vec![(
+                "old/data.txt.gz".to_string(),
+                Status::Moved,
+                Some("data.txt".to_string())
+            )]
+        );
+        assert!(entries[0].decompressed);
+        let entries = diff_dirs(&left, &right, false);
+        assert!(entries.iter().all(|e| e.status != Status::Moved));
+    }
+
+    #[test]
+    fn finds_moves_into_listed_files_without_reading_them() {
+        let tmp = TempDir::new("diff-listed");
+        let content = b"listed in a manifest".repeat(20);
+        let left = tmp.dir("left", &[("data.txt", &content)]);
+        let hash = hash_content(Path::new(&left.files[0].path), true)
+            .unwrap()
+            .hash;
+        let mut listed = crate::testing::file(Path::new("manifest.json/old/data.txt.gz"), 70);
+        listed.hash = Some(hash);
+        listed.decompressed = true;
+        let right = Dir::new("manifest.json".to_string(), vec![listed], Vec::new());
+
+        let entries = diff_dirs(&left, &right, true);
+        assert_eq!(
+            statuses(&entries),
+            vec![(
+                "old/data.txt.gz".to_string(),
+                Status::Moved,
+                Some("data.txt".to_string())
+            )]
+        );
+        assert!(entries[0].decompressed);
+    }
+}
@@ -0,0 +1,161 @@
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read},
+    path::Path,
+};
+
+use crate::{
+    cancel, checkpoint,
+    decompress::{codec_of, open, Codec},
+    progress, Dir, File,
+};
+
+const BUF_SIZE: usize = 64 * 1024;
+
+/// Hash of the bytes a file holds, after decompressing it when asked to.
+pub struct Content {
+    pub hash: String,
+    pub len: u64,
+    pub decompressed: bool,
+}
+
+pub fn hash_reader<R: Read>(reader: R) -> io::Result<String> {
+    Ok(hash_counted(reader)?.0)
+}
+
+/// Feeds the bytes of `reader` to `update` and returns how many there were.
+pub fn feed<R: Read>(mut reader: R, mut update: impl FnMut(&[u8])) -> io::Result<u64> {
+    let mut buf = vec![0u8; BUF_SIZE];
+    let mut len = 0;
+    loop {
+        if cancel::stopped() {
+            return Err(cancel::stopped_err());
+        }
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        update(&buf[..n]);
+        len += n as u64;
+        progress::bytes_hashed(n as u64);
+    }
+    Ok(len)
+}
+
+fn hash_counted<R: Read>(reader: R) -> io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let len = feed(reader, |bytes| hasher.update(bytes))?;
+    Ok((format!("{:x}", hasher.finalize()), len))
+}
+
+/// MD5 of the raw bytes of the file at `path`, for checksum files.
+pub fn md5_file(path: &Path) -> io::Result<String> {
+    let mut hasher = Md5::new();
+    feed(fs::File::open(path)?, |bytes| hasher.update(bytes))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// CRC-32 of the raw bytes of the file at `path`, for SFV files.
+pub fn crc32_file(path: &Path) -> io::Result<String> {
+    let mut hasher = crc32fast::Hasher::new();
+    feed(fs::File::open(path)?, |bytes| hasher.update(bytes))?;
+    Ok(format!("{:08x}", hasher.finalize()))
+}
+
+/// Hashes the file at `path`. With `decompress`, files in a known compression
+/// format are hashed on their decompressed bytes, falling back to the raw
+/// bytes when they can't be decoded. Hashes kept in a checkpoint are reused
+/// while the file is unchanged.
+pub fn hash_content(path: &Path, decompress: bool) -> io::Result<Content> {
+    let codec = codec_of(path).filter(|_| decompress);
+    if let Some(content) = checkpoint::cached_hash(path, codec.is_some()) {
+        return Ok(content);
+    }
+    let content = hash_file(path, codec)?;
+    checkpoint::hashed(path, &content);
+    Ok(content)
+}
+
+fn hash_file(path: &Path, codec: Option<Codec>) -> io::Result<Content> {
+    if let Some(codec) = codec {
+        match open(path, codec).and_then(hash_counted) {
+            Ok((hash, len)) => {
+                return Ok(Content {
+                    hash,
+                    len,
+                    decompressed: true,
+                })
+            }
+            Err(e) if cancel::stopped() => return Err(e),
+            Err(e) => eprintln!("Decompress err: {}, File {:?}", e, path),
+        }
+    }
+    let (hash, len) = hash_counted(fs::File::open(path)?)?;
+    Ok(Content {
+        hash,
+        len,
+        decompressed: false,
+    })
+}
+
+/// Sets the hash of `file` and returns the length of the hashed content.
+pub fn set_hash(file: &mut File, decompress: bool) -> Option<u64> {
+    match hash_content(Path::new(&file.path), decompress) {
+        Ok(content) => {
+            file.hash = Some(content.hash);
+            file.decompressed = content.decompressed;
+            Some(content.len)
+        }
+        Err(e) => {
+            if !cancel::stopped() {
+                eprintln!("Hash err: {}, File {:?}", e, file.path);
+                progress::error();
+            }
+            None
+        }
+    }
+}
+
+/// Hashes files whose size is shared with at least one other file, since
+/// only those can have a duplicate. With `decompress`, compressed files are
+/// always hashed, since their content size is unknown until then. Files are
+/// hashed one size at a time, copies with the same name next to each other,
+/// so that a stopped run still comes out with whole groups.
+pub fn hash_candidates(dirs: &mut [Dir], decompress: bool) {
+    let mut files: Vec<&mut File> = dirs.iter_mut().flat_map(|d| d.files.iter_mut()).collect();
+    let content_sizes: Vec<u64> = files
+        .iter_mut()
+        .map(|file| {
+            let compressed = codec_of(Path::new(&file.path)).is_some();
+            if decompress && compressed && file.hash.is_none() {
+                set_hash(file, decompress).unwrap_or(file.size)
+            } else {
+                file.size
+            }
+        })
+        .collect();
+
+    let mut sizes: HashMap<u64, usize> = HashMap::new();
+    for size in content_sizes.iter() {
+        *sizes.entry(*size).or_default() += 1;
+    }
+
+    let mut candidates: Vec<usize> = (0..files.len())
+        .filter(|&idx| files[idx].hash.is_none() && sizes[&content_sizes[idx]] > 1)
+        .collect();
+    candidates.sort_by(|&a, &b| {
+        (content_sizes[a], &files[a].name).cmp(&(content_sizes[b], &files[b].name))
+    });
+    let bytes_total = candidates.iter().map(|&idx| files[idx].size).sum();
+    progress::set_phase("hash", Some(bytes_total));
+
+    for idx in candidates {
+        if cancel::stopped() {
+            break;
+        }
+        set_hash(files[idx], decompress);
+    }
+}
@@ -1,16 +1,71 @@
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    env, fs,
+    fs, io,
     path::{Path, PathBuf},
-    ptr,
+    process,
+    time::SystemTime,
 };
 
-#[derive(Debug, Eq, Hash, Clone, Serialize, Deserialize)]
+mod archive;
+mod args;
+mod cancel;
+mod checkpoint;
+mod chunks;
+mod config;
+mod db;
+mod decompress;
+mod diff;
+mod hash;
+mod manifest;
+mod merge;
+mod metadata;
+mod minhash;
+mod names;
+mod phash;
+mod progress;
+mod report;
+mod review;
+mod similar;
+mod spill;
+mod stats;
+mod subtree;
+mod sync;
+#[cfg(test)]
+mod testing;
+mod verify;
+mod watch;
+
+use archive::{is_archive, is_member, read_members};
+use args::{parse_args, MatchBy, Mode, Options, Scope};
+use checkpoint::Child;
+use chunks::chunk_dirs;
+use diff::{diff_dirs, Status};
+use hash::{hash_candidates, hash_content};
+use merge::{merge_dirs, Reason};
+use metadata::{add_diff_mismatches, add_mismatches, Meta, Mismatch};
+use minhash::find_similar_texts;
+use names::name_key;
+use phash::find_similar_images;
+use progress::human_bytes;
+use report::{is_partial, Report};
+use similar::{as_duplicates, group_pairs};
+use stats::duplicate_stats;
+use subtree::{find_dir_duplicates, fingerprint, Covered, Subtree};
+use sync::{sync_dirs, Outcome};
+use verify::verify_dir;
+
+#[derive(Debug, Eq, Clone, Serialize, Deserialize)]
 struct File {
     name: String,
     path: String,
     size: u64,
+    modified: Option<SystemTime>,
+    hash: Option<String>,
+    /// The hash was taken over the decompressed content.
+    decompressed: bool,
+    /// Mode bits, ownership and extended attributes, with `--metadata`.
+    meta: Option<Meta>,
 }
 
 impl PartialEq for File {
@@ -22,11 +77,16 @@ impl PartialEq for File {
 struct Dir {
     name: String,
     files: Vec<File>,
+    subtrees: Vec<Subtree>,
 }
 
 impl Dir {
-    fn new(name: String, files: Vec<File>) -> Self {
-        Self { name, files }
+    fn new(name: String, files: Vec<File>, subtrees: Vec<Subtree>) -> Self {
+        Self {
+            name,
+            files,
+            subtrees,
+        }
     }
 }
 
@@ -35,145 +95,573 @@ struct Duplicate<'a> {
     file_name: &'a str,
     first_dir_match: &'a str,
     second_dir_match: Vec<String>,
+    scope: Scope,
+    /// The root of every file, the first one first.
+    roots: Vec<usize>,
+    #[serde(borrow, skip_serializing_if = "Vec::is_empty")]
+    decompressed: Vec<&'a str>,
+    #[serde(borrow, skip_serializing_if = "Vec::is_empty", default)]
+    metadata_mismatches: Vec<Mismatch<'a>>,
 }
 
-fn parse_args() -> Result<Vec<PathBuf>, String> {
-    let args: Vec<String> = env::args().skip(1).take(2).collect();
-
-    if args.is_empty() {
-        return Err("Provide 1 or 2 arguments...".into());
-    }
-
-    let mut skip_count = 0;
-    if args.len() > 1 && args[0] == args[1] {
-        skip_count = 1;
-    }
-
-    args.into_iter()
-        .skip(skip_count)
-        .map(|arg| {
-            let p = PathBuf::from(arg);
-            if !p.exists() {
-                Err(format!("Path does not exist: {}", p.display()))
-            } else if !p.is_dir() {
-                Err(format!("Path is not a dir: {}", p.display()))
-            } else {
-                Ok(p)
-            }
-        })
-        .collect()
-}
-
-fn read_dirs(paths: Vec<PathBuf>) -> Result<Vec<Dir>, String> {
+fn read_dirs(paths: Vec<PathBuf>, options: &Options) -> Result<Vec<Dir>, String> {
     paths
         .into_iter()
-        .map(|path| {
+        .enumerate()
+        .map(|(root, path)| {
+            spill::set_root(root, &path);
+            if path.is_file() {
+                let mut dir = manifest::read(&path, options)?;
+                spill::flush(&mut dir.files);
+                return Ok(dir);
+            }
             let mut files: Vec<File> = Vec::new();
-            if let Err(e) = walk_dir(&path, &mut files) {
+            let mut subtrees: Vec<Subtree> = Vec::new();
+            let subtrees_arg = if options.dirs {
+                Some(&mut subtrees)
+            } else {
+                None
+            };
+            if let Err(e) = walk_dir(&path, &mut files, subtrees_arg, options) {
                 Err(format!(
                     "Couldn't read directory: {}. Error: {}",
                     path.display(),
                     e
                 ))
             } else {
-                Ok(Dir::new(path.display().to_string(), files))
+                spill::flush(&mut files);
+                Ok(Dir::new(path.display().to_string(), files, subtrees))
             }
         })
         .collect()
 }
 
-fn walk_dir(dir: &Path, files: &mut Vec<File>) -> Result<(), Box<dyn std::error::Error>> {
-    let entries = match fs::read_dir(dir) {
-        Ok(entries) => entries,
+/// Lists the entries of `dir` as names, telling dirs apart from the rest.
+fn list_dir(dir: &Path) -> std::io::Result<Vec<(String, bool)>> {
+    Ok(fs::read_dir(dir)?
+        .flatten()
+        .filter_map(|entry| {
+            let file_type = entry.file_type().ok()?;
+            let name = entry
+                .file_name()
+                .into_string()
+                .expect("Invalid Unicode data");
+            Some((name, file_type.is_dir()))
+        })
+        .collect())
+}
+
+/// Walks `dir` recursively. When `subtrees` is given, every file is hashed and
+/// the fingerprint of each fully read dir is recorded bottom-up. Dirs walked
+/// to their end are journaled to the checkpoint, and those it already holds
+/// are put back from it instead of being walked again.
+fn walk_dir(
+    dir: &Path,
+    files: &mut Vec<File>,
+    mut subtrees: Option<&mut Vec<Subtree>>,
+    options: &Options,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    if let Some(fp) = checkpoint::replay(dir, files, subtrees.as_deref_mut()) {
+        return Ok(fp);
+    }
+    let listing = match list_dir(dir) {
+        Ok(listing) => listing,
         Err(e) => {
             eprintln!("Err {:?}, Dir {:?}", e, dir);
-            return Ok(());
+            progress::error();
+            return Ok(None);
         }
     };
-    for entry in entries {
-        if let Ok(entry) = entry {
-            let path = entry.path();
-
-            if let Ok(file_type) = entry.file_type() {
-                if file_type.is_dir() {
-                    walk_dir(&path, files)?;
-                } else {
-                    let size = match entry.metadata() {
-                        Ok(metadata) => metadata.len(),
-                        Err(e) => {
-                            eprintln!("Metadata size err: {}", e);
-                            0
+    let first_file = files.len();
+    let mut children: Vec<(char, String, String)> = Vec::new();
+    let mut complete = true;
+    let mut listed = true;
+    let journal = checkpoint::enabled();
+    let mut journaled: Vec<Child> = Vec::new();
+
+    for (name, is_dir) in listing {
+        if cancel::stopped() {
+            complete = false;
+            listed = false;
+            break;
+        }
+        let path = dir.join(&name);
+
+        if is_dir {
+            if journal {
+                journaled.push(Child::Dir(name.clone()));
+            }
+            match walk_dir(&path, files, subtrees.as_deref_mut(), options)? {
+                Some(fp) => children.push(('d', name, fp)),
+                None => complete = false,
+            }
+        } else {
+            let (size, modified, meta, is_symlink) = match fs::symlink_metadata(&path) {
+                Ok(metadata) => (
+                    metadata.len(),
+                    metadata.modified().ok(),
+                    options.metadata.then(|| Meta::read(&path, &metadata)),
+                    metadata.is_symlink(),
+                ),
+                Err(e) => {
+                    eprintln!("Metadata size err: {}", e);
+                    progress::error();
+                    (0, None, None, false)
+                }
+            };
+
+            // A symlink stands for where it points, which needn't exist.
+            let (hash, decompressed) = if subtrees.is_some() && is_symlink {
+                match fs::read_link(&path) {
+                    Ok(target) => children.push(('l', name.clone(), target.display().to_string())),
+                    Err(e) => {
+                        eprintln!("Link err: {}, File {:?}", e, path);
+                        progress::error();
+                        complete = false;
+                    }
+                }
+                (None, false)
+            } else if subtrees.is_some() {
+                match hash_content(&path, options.decompress) {
+                    Ok(content) => {
+                        children.push(('f', name.clone(), content.hash.clone()));
+                        (Some(content.hash), content.decompressed)
+                    }
+                    Err(e) => {
+                        if !cancel::stopped() {
+                            eprintln!("Hash err: {}, File {:?}", e, path);
+                            progress::error();
                         }
-                    };
-
-                    files.push(File {
-                        name: entry
-                            .file_name()
-                            .into_string()
-                            .expect("Invalid Unicode data"),
-                        path: path.to_str().expect("Invalid Unicode data").to_string(),
-                        size,
-                    });
+                        complete = false;
+                        (None, false)
+                    }
                 }
+            } else {
+                (None, false)
+            };
+
+            progress::file_walked();
+            let at = files.len();
+            files.push(File {
+                name,
+                path: path.to_str().expect("Invalid Unicode data").to_string(),
+                size,
+                modified,
+                hash,
+                decompressed,
+                meta,
+            });
+
+            if options.archives && is_archive(&path) {
+                match read_members(&path, files) {
+                    Err(e) if !cancel::stopped() => {
+                        eprintln!("Archive err: {}, File {:?}", e, path);
+                        progress::error();
+                    }
+                    _ => (),
+                }
+            }
+            if journal {
+                journaled.extend(files[at..].iter().cloned().map(Child::File));
             }
+            spill::spill(files);
         }
     }
 
-    Ok(())
-}
-
-fn find_duplicates<'a>(files1: &'a Vec<File>, files2: &'a Vec<File>) -> Vec<Duplicate<'a>> {
-    let mut matches: HashMap<String, Duplicate> = if !ptr::eq(files1, files2) {
-        files1
-            .into_iter()
-            .map(|file| {
-                (
-                    file.name.clone(),
-                    Duplicate {
-                        file_name: &file.name,
-                        first_dir_match: &file.path,
-                        second_dir_match: Vec::new(),
-                    },
-                )
-            })
-            .collect()
-    } else {
-        HashMap::new()
+    let subtree = match subtrees {
+        Some(subtrees) if complete => {
+            let real_files = files[first_file..].iter().filter(|f| !is_member(&f.path));
+            let subtree = Subtree {
+                path: dir.to_str().expect("Invalid Unicode data").to_string(),
+                fingerprint: fingerprint(children),
+                file_count: real_files.clone().count(),
+                size: real_files.map(|f| f.size).sum(),
+            };
+            subtrees.push(subtree.clone());
+            Some(subtree)
+        }
+        _ => None,
     };
+    if listed {
+        checkpoint::dir_done(dir, journaled, subtree.as_ref());
+    }
+    Ok(subtree.map(|s| s.fingerprint))
+}
 
-    for file in files2 {
-        match matches.get_mut(&file.name) {
-            Some(dups) => dups.second_dir_match.push(file.path.clone()),
-            None => {
-                matches.insert(
-                    file.name.clone(),
-                    Duplicate {
-                        file_name: &file.name,
-                        first_dir_match: &file.path,
-                        second_dir_match: Vec::new(),
-                    },
-                );
+/// Groups the files of all dirs by `key`, keeping the groups of `scope`.
+/// Files without a key are left out.
+fn find_duplicates<K>(dirs: &[Dir], key: K, scope: Option<Scope>) -> Vec<Duplicate<'_>>
+where
+    K: Fn(&File) -> Option<String>,
+{
+    let mut groups: HashMap<String, Vec<(usize, &File)>> = HashMap::new();
+    for (root, dir) in dirs.iter().enumerate() {
+        for file in dir.files.iter() {
+            if let Some(file_key) = key(file) {
+                groups.entry(file_key).or_default().push((root, file));
             }
         }
     }
 
-    matches
+    groups
         .into_values()
-        .filter(|m| !m.second_dir_match.is_empty())
+        .filter(|group| group.len() > 1)
+        .flat_map(|mut group| {
+            group.sort_by(|a, b| (a.0, &a.1.path).cmp(&(b.0, &b.1.path)));
+            Scope::split(scope, group)
+        })
+        .map(|group| {
+            let first = group[0].1;
+            Duplicate {
+                file_name: &first.name,
+                first_dir_match: &first.path,
+                second_dir_match: group[1..].iter().map(|(_, f)| f.path.clone()).collect(),
+                scope: Scope::of(group.iter().map(|(root, _)| *root)),
+                roots: group.iter().map(|(root, _)| *root).collect(),
+                decompressed: group
+                    .iter()
+                    .filter(|(_, f)| f.decompressed)
+                    .map(|(_, f)| f.path.as_str())
+                    .collect(),
+                metadata_mismatches: Vec::new(),
+            }
+        })
         .collect()
 }
 
-fn entry() -> Result<(), Box<dyn std::error::Error>> {
-    let paths = parse_args()?;
+/// Exit codes, so that scripts can tell the outcomes of a run apart. A
+/// partial report takes precedence over what it found.
+const EXIT_NOTHING_FOUND: i32 = 0;
+const EXIT_FOUND: i32 = 1;
+const EXIT_FAILED: i32 = 2;
+const EXIT_PARTIAL: i32 = 3;
+
+/// Diffs the two dirs and tells whether they differ.
+fn diff(left: &Dir, right: &Dir, options: &Options) -> Result<bool, Box<dyn std::error::Error>> {
+    progress::set_phase("diff", None);
+    let mut entries = diff_dirs(left, right, options.decompress);
+    if options.metadata {
+        add_diff_mismatches(left, right, &mut entries, options.mtime_tolerance);
+    }
+    progress::finish();
+    checkpoint::finish();
+
+    let count = |status: Status| entries.iter().filter(|e| e.status == status).count();
+    println!("Only in first folder: {:?}", count(Status::OnlyLeft));
+    println!("Only in second folder: {:?}", count(Status::OnlyRight));
+    println!("Identical: {:?}", count(Status::Identical));
+    println!("Changed: {:?}", count(Status::Changed));
+    println!(
+        "Moved or renamed: {:?}",
+        count(Status::Moved) + count(Status::Renamed)
+    );
+    if cancel::reason().is_some() {
+        println!("Unchecked: {:?}", count(Status::Unchecked));
+    }
+    let mismatched = entries
+        .iter()
+        .filter(|e| !e.metadata_mismatches.is_empty())
+        .count();
+    if options.metadata {
+        println!("Files with metadata mismatches: {:?}", mismatched);
+    }
+
+    let differ = mismatched > 0 || entries.iter().any(|e| e.status != Status::Identical);
+    let mut report = Report::new(options);
+    report.diff = Some(entries);
+    report.write("./diff.json")?;
+    Ok(differ)
+}
+
+/// Copies what only the first dir has to the second one and tells whether
+/// anything was missing.
+fn sync(left: &Dir, right: &Dir, options: &Options) -> Result<bool, Box<dyn std::error::Error>> {
+    progress::set_phase("diff", None);
+    let entries = diff_dirs(left, right, options.decompress);
+    let transfers = sync_dirs(&entries, left, right, options);
+    progress::finish();
+    checkpoint::finish();
+
+    let count = |outcome: Outcome| transfers.iter().filter(|t| t.outcome == outcome).count();
+    let bytes = |outcome: Outcome| {
+        transfers
+            .iter()
+            .filter(|t| t.outcome == outcome)
+            .map(|t| t.size)
+            .sum()
+    };
+    if options.dry_run {
+        println!("To copy: {:?}", count(Outcome::Planned));
+        println!("Bytes to copy: {}", human_bytes(bytes(Outcome::Planned)));
+    } else {
+        println!("Copied: {:?}", count(Outcome::Copied));
+        println!("Bytes copied: {}", human_bytes(bytes(Outcome::Copied)));
+    }
+    if options.skip_existing_content {
+        println!("Already there: {:?}", count(Outcome::Skipped));
+    }
+    println!("Failed: {:?}", count(Outcome::Failed));
+
+    let missing = transfers.iter().any(|t| t.outcome != Outcome::Skipped);
+    let mut report = Report::new(options);
+    report.sync = Some(transfers);
+    report.write("./sync.json")?;
+    Ok(missing)
+}
+
+/// Merges the two dirs into the destination and tells whether they differ.
+fn merge(left: &Dir, right: &Dir, options: &Options) -> Result<bool, Box<dyn std::error::Error>> {
+    progress::set_phase("diff", None);
+    let entries = diff_dirs(left, right, false);
+    let decisions = merge_dirs(&entries, left, right, options);
+    progress::finish();
+    checkpoint::finish();
+
+    let count = |reason: Reason| decisions.iter().filter(|d| d.reason == reason).count();
+    println!("Only in first folder: {:?}", count(Reason::OnlyLeft));
+    println!("Only in second folder: {:?}", count(Reason::OnlyRight));
+    println!("Identical: {:?}", count(Reason::Identical));
+    println!(
+        "Same content as another path: {:?}",
+        decisions.iter().filter(|d| d.stored_as.is_some()).count()
+    );
+    println!(
+        "Conflicts: {:?}",
+        entries
+            .iter()
+            .filter(|e| e.status == Status::Changed)
+            .count()
+    );
+    let count = |outcome: Outcome| decisions.iter().filter(|d| d.outcome == outcome).count();
+    if !options.dry_run {
+        println!("Copied: {:?}", count(Outcome::Copied));
+        println!("Linked: {:?}", count(Outcome::Linked));
+    }
+    println!("Failed: {:?}", count(Outcome::Failed));
+
+    let differ = entries.iter().any(|e| e.status != Status::Identical);
+    let mut report = Report::new(options);
+    report.merge = Some(decisions);
+    report.write("./merge.json")?;
+    Ok(differ)
+}
+
+/// Verifies `dir` against its checksum files and tells whether any check failed.
+fn verify(
+    dir: &Dir,
+    checksum_files: &[PathBuf],
+    options: &Options,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let checks = verify_dir(dir, checksum_files)?;
+    progress::finish();
+    checkpoint::finish();
+
+    let count = |status: verify::Status| checks.iter().filter(|c| c.status == status).count();
+    println!("Verified: {:?}", count(verify::Status::Ok));
+    println!("Missing: {:?}", count(verify::Status::Missing));
+    println!("Extra: {:?}", count(verify::Status::Extra));
+    println!("Corrupted: {:?}", count(verify::Status::Corrupted));
+    if count(verify::Status::Unchecked) > 0 {
+        println!("Unchecked: {:?}", count(verify::Status::Unchecked));
+    }
 
-    let dirs = read_dirs(paths)?;
+    let failed = checks.iter().any(|c| c.status != verify::Status::Ok);
+    let mut report = Report::new(options);
+    report.verify = Some(checks);
+    report.write("./verify.json")?;
+    Ok(failed)
+}
 
-    if dirs.len() == 0 {
-        return Err("No dirs found".into());
+/// Chunks the files of `dirs` and tells whether any chunk is shared.
+fn chunks(dirs: &[Dir], options: &Options) -> Result<bool, Box<dyn std::error::Error>> {
+    let stats = chunk_dirs(dirs, options.chunk_size, options.top);
+    progress::finish();
+    checkpoint::finish();
+
+    let shared = stats.total_bytes - stats.unique_bytes;
+    println!("Files chunked: {:?}", stats.files);
+    println!("Chunks: {:?}", stats.chunks);
+    println!("Unique chunks: {:?}", stats.unique_chunks);
+    println!("Total: {}", human_bytes(stats.total_bytes));
+    println!("Unique: {}", human_bytes(stats.unique_bytes));
+    println!("Shared: {}", human_bytes(shared));
+    println!(
+        "Whole-file duplicates: {}",
+        human_bytes(stats.whole_file_savings)
+    );
+    if !stats.top_files.is_empty() {
+        println!("Files sharing the most:");
+        for file in stats.top_files.iter() {
+            println!(
+                "  {} of {} in {}",
+                human_bytes(file.shared_bytes),
+                human_bytes(file.size),
+                file.path
+            );
+        }
+    }
+
+    let mut report = Report::new(options);
+    report.chunks = Some(stats);
+    report.write("./chunks.json")?;
+    Ok(shared > 0)
+}
+
+/// Content matching with the file index kept on disk, for `--max-memory`.
+fn spilled_duplicates(roots: usize, options: &Options) -> Result<bool, Box<dyn std::error::Error>> {
+    let mut index = options
+        .sqlite
+        .as_deref()
+        .map(db::Index::create)
+        .transpose()?;
+    if let Some(index) = index.as_mut() {
+        spill::each_file(|file| index.add_file(&file).map_err(io::Error::other))?;
+    }
+    let (groups, stats) = spill::find_duplicates(
+        options.decompress,
+        options.scope,
+        options.top,
+        |file_name, scope, members| match index.as_mut() {
+            Some(index) => index
+                .add_group(file_name, scope, members)
+                .map_err(io::Error::other),
+            None => Ok(()),
+        },
+    )?;
+    progress::finish();
+    checkpoint::finish();
+    if let Some(index) = index {
+        index.finish(&stats.roots, options)?;
+    }
+
+    println!("First folder total duplicates: {:?}", groups.count);
+    println!("Second folder total duplicates: {:?}", groups.copies);
+    if roots > 1 {
+        println!("Cross-root groups: {:?}", groups.cross);
+        println!("Intra-root groups: {:?}", groups.count - groups.cross);
+    }
+    stats.print();
+
+    let mut report = Report::new(options);
+    let found = groups.count > 0;
+    report.stats = Some(stats);
+    report.spilled = Some(groups);
+    report.write("./duplicates.json")?;
+    Ok(found)
+}
+
+/// Runs the scan and returns the exit code telling what came of it.
+fn entry() -> Result<i32, Box<dyn std::error::Error>> {
+    let args = parse_args()?;
+    if args.mode == Mode::Review {
+        review::review(&args.paths[0])?;
+        return Ok(EXIT_NOTHING_FOUND);
+    }
+
+    let options = args.options;
+    progress::init(options.progress_json, !options.no_progress)?;
+    cancel::init(&options.budget);
+    if let Some(output) = options.checkpoint.as_ref().or(options.resume.as_ref()) {
+        checkpoint::init(output.clone(), options.resume.as_deref(), &options)?;
+    }
+    // Kept until the report is written, and removed on the way out.
+    let _spill_dir = options
+        .max_memory
+        .map(|max_memory| spill::init(max_memory, options.decompress))
+        .transpose()?;
+    let exit_code = |found: bool| {
+        if found {
+            EXIT_FOUND
+        } else {
+            EXIT_NOTHING_FOUND
+        }
+    };
+    if args.mode == Mode::Query {
+        return db::query(&args.paths[0], &options.filter).map(exit_code);
+    }
+    if args.mode == Mode::Watch {
+        // Watching ends with a stop, which leaves nothing partial behind.
+        return watch::watch(args.paths, &options).map(exit_code);
+    }
+    let found = scan(args.mode, args.paths, &options)?;
+    Ok(if is_partial() {
+        EXIT_PARTIAL
     } else {
-        let duplicates2 = find_duplicates(&dirs[0].files, &dirs.get(1).unwrap_or(&dirs[0]).files);
+        exit_code(found)
+    })
+}
+
+/// Scans the dirs at `paths`, writes the report and tells whether duplicates
+/// or differences were found.
+fn scan(
+    mode: Mode,
+    paths: Vec<PathBuf>,
+    options: &Options,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    if mode == Mode::Verify {
+        let dirs = read_dirs(paths[..1].to_vec(), options)?;
+        return verify(&dirs[0], &paths[1..], options);
+    }
+    let mut dirs = read_dirs(paths, options)?;
+
+    if dirs.is_empty() {
+        Err("No dirs found".into())
+    } else if mode == Mode::Diff {
+        diff(&dirs[0], &dirs[1], options)
+    } else if mode == Mode::Sync {
+        sync(&dirs[0], &dirs[1], options)
+    } else if mode == Mode::Merge {
+        merge(&dirs[0], &dirs[1], options)
+    } else if mode == Mode::Chunks {
+        chunks(&dirs, options)
+    } else if mode == Mode::Manifest {
+        manifest::write(&mut dirs[0], options, "./manifest.json")?;
+        checkpoint::finish();
+        Ok(false)
+    } else if options.max_memory.is_some() {
+        spilled_duplicates(dirs.len(), options)
+    } else {
+        // With --dirs the walk already hashed every file it could, and left
+        // symlinks unhashed.
+        if options.match_by == MatchBy::Content && !options.dirs {
+            hash_candidates(&mut dirs, options.decompress);
+        }
+        let key = |file: &File| match options.match_by {
+            MatchBy::Name => Some(name_key(file, &options.name_match)),
+            _ => file.hash.clone(),
+        };
+        let mut similar = Vec::new();
+        let mut duplicates2 = match options.match_by {
+            MatchBy::Image => {
+                progress::set_phase("images", None);
+                let pairs = find_similar_images(&dirs, options.max_distance);
+                similar = group_pairs(pairs, &dirs, options.scope);
+                as_duplicates(&similar)
+            }
+            MatchBy::Text => {
+                progress::set_phase("texts", None);
+                let pairs = find_similar_texts(&dirs, options.threshold);
+                similar = group_pairs(pairs, &dirs, options.scope);
+                as_duplicates(&similar)
+            }
+            _ => find_duplicates(&dirs, key, options.scope),
+        };
+
+        // Stats count the file groups inside duplicate dirs too, since those
+        // hold the bytes the dirs would free.
+        let identical = options.match_by == MatchBy::Content;
+        let stats = duplicate_stats(&dirs, &duplicates2, options.top, identical);
+        let dir_duplicates = find_dir_duplicates(&dirs, options.scope);
+        if !options.all_files && !dir_duplicates.is_empty() {
+            let covered = Covered::new(&dir_duplicates);
+            duplicates2.retain(|dup| {
+                !(covered.contains(dup.first_dir_match)
+                    && dup.second_dir_match.iter().all(|m| covered.contains(m)))
+            });
+        }
 
-        let serialized = serde_json::to_string_pretty(&duplicates2)?;
+        progress::finish();
+        checkpoint::finish();
 
         println!("First folder total duplicates: {:?}", duplicates2.len());
         println!(
@@ -182,17 +670,50 @@ fn entry() -> Result<(), Box<dyn std::error::Error>> {
                 .iter()
                 .fold(0, |acc, dup| acc + dup.second_dir_match.len())
         );
+        if dirs.len() > 1 {
+            let cross = duplicates2
+                .iter()
+                .filter(|dup| dup.scope == Scope::CrossRoot)
+                .count();
+            println!("Cross-root groups: {:?}", cross);
+            println!("Intra-root groups: {:?}", duplicates2.len() - cross);
+        }
+        if options.dirs {
+            println!("Duplicate dirs: {:?}", dir_duplicates.len());
+        }
+        if options.metadata {
+            add_mismatches(&dirs, &mut duplicates2, options.mtime_tolerance);
+            println!(
+                "Groups with metadata mismatches: {:?}",
+                duplicates2
+                    .iter()
+                    .filter(|dup| !dup.metadata_mismatches.is_empty())
+                    .count()
+            );
+        }
+        stats.print();
+        if let Some(path) = options.sqlite.as_deref() {
+            db::write_duplicates(path, &dirs, &duplicates2, &stats.roots, options)?;
+        }
 
-        let output_file = "./duplicates.json";
-        fs::write(output_file, serialized)?; // return
-    };
-
-    Ok(())
+        let found = !duplicates2.is_empty() || !dir_duplicates.is_empty();
+        let mut report = Report::new(options);
+        report.stats = Some(stats);
+        report.duplicates = Some(duplicates2);
+        report.dir_duplicates = dir_duplicates;
+        report.similar = similar;
+        report.write("./duplicates.json")?;
+        Ok(found)
+    }
 }
 
 fn main() {
-    match entry() {
-        Ok(()) => (),
-        Err(e) => eprintln!("error: {}", e),
-    }
+    let code = match entry() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            EXIT_FAILED
+        }
+    };
+    process::exit(code);
 }
@@ -0,0 +1,209 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{BufWriter, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+use crate::{args::Options, cancel, hash::hash_content, progress, Dir, File};
+
+const VERSION: u32 = 1;
+
+/// A portable listing of a tree, to compare it with trees on other machines.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub root: String,
+    /// Compressed files were hashed on their decompressed content.
+    pub decompress: bool,
+    /// The listing was stopped before every file was hashed.
+    pub partial: bool,
+    pub files: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    /// Path relative to the root, with `/` separators.
+    pub path: String,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub modified: Option<SystemTime>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub hash: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub decompressed: bool,
+}
+
+/// Hashes every file of `dir` and writes its manifest to `output_file`.
+pub fn write(
+    dir: &mut Dir,
+    options: &Options,
+    output_file: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let bytes_total = dir
+        .files
+        .iter()
+        .filter(|f| f.hash.is_none())
+        .map(|f| f.size)
+        .sum();
+    progress::set_phase("hash", Some(bytes_total));
+    for file in dir.files.iter_mut().filter(|f| f.hash.is_none()) {
+        if cancel::stopped() {
+            break;
+        }
+        match hash_content(Path::new(&file.path), options.decompress) {
+            Ok(content) => {
+                file.hash = Some(content.hash);
+                file.decompressed = content.decompressed;
+            }
+            Err(e) => {
+                if !cancel::stopped() {
+                    eprintln!("Hash err: {}, File {:?}", e, file.path);
+                    progress::error();
+                }
+            }
+        }
+    }
+    progress::finish();
+
+    let manifest = Manifest {
+        version: VERSION,
+        root: dir.name.clone(),
+        decompress: options.decompress,
+        partial: crate::report::is_partial(),
+        files: dir
+            .files
+            .iter()
+            .map(|file| Entry {
+                path: Path::new(&file.path)
+                    .strip_prefix(&dir.name)
+                    .unwrap_or(Path::new(&file.path))
+                    .to_str()
+                    .expect("Invalid Unicode data")
+                    .to_string(),
+                size: file.size,
+                modified: file.modified,
+                hash: file.hash.clone(),
+                decompressed: file.decompressed,
+            })
+            .collect(),
+    };
+    println!("Files listed: {:?}", manifest.files.len());
+    if manifest.partial {
+        eprintln!("Listing stopped early, writing a partial manifest");
+    }
+
+    let mut out = BufWriter::new(fs::File::create(output_file)?);
+    serde_json::to_writer_pretty(&mut out, &manifest)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Reads the manifest at `path` as a dir named after the manifest file, so
+/// its files can be matched against walked ones by name or by hash.
+pub fn read(path: &Path, options: &Options) -> Result<Dir, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't read manifest: {}. Error: {}", path.display(), e))?;
+    let manifest: Manifest = serde_json::from_str(&text)
+        .map_err(|e| format!("Invalid manifest: {}. Error: {}", path.display(), e))?;
+    if manifest.version != VERSION {
+        return Err(format!(
+            "Unsupported manifest version {}: {}",
+            manifest.version,
+            path.display()
+        ));
+    }
+    if manifest.decompress != options.decompress {
+        return Err(format!(
+            "Manifest {} was hashed {} --decompress, scan it the same way",
+            path.display(),
+            if manifest.decompress {
+                "with"
+            } else {
+                "without"
+            }
+        ));
+    }
+    if manifest.partial {
+        eprintln!("Manifest {} is partial", path.display());
+    }
+
+    let name = path.display().to_string();
+    let files = manifest
+        .files
+        .into_iter()
+        .map(|entry| File {
+            name: entry
+                .path
+                .rsplit('/')
+                .next()
+                .unwrap_or(&entry.path)
+                .to_string(),
+            path: format!("{}/{}", name, entry.path),
+            size: entry.size,
+            modified: entry.modified,
+            hash: entry.hash,
+            decompressed: entry.decompressed,
+            meta: None,
+        })
+        .collect();
+    Ok(Dir::new(name, files, Vec::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn reads_back_what_it_wrote() {
+        let tmp = TempDir::new("manifest-roundtrip");
+        let mut dir = tmp.dir("tree", &[("a.txt", b"abc"), ("sub/b.txt", b"")]);
+        let output = tmp.path.join("manifest.json");
+        let options = Options::default();
+        write(&mut dir, &options, output.to_str().unwrap()).unwrap();
+
+        let listed = read(&output, &options).unwrap();
+        assert_eq!(listed.name, output.display().to_string());
+        let files: Vec<(&str, String, u64)> = listed
+            .files
+            .iter()
+            .map(|f| (f.name.as_str(), f.path.clone(), f.size))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("a.txt", format!("{}/a.txt", listed.name), 3),
+                ("b.txt", format!("{}/sub/b.txt", listed.name), 0),
+            ]
+        );
+        for (walked, listed) in dir.files.iter().zip(listed.files.iter()) {
+            assert!(listed.hash.is_some());
+            assert_eq!(listed.hash, walked.hash);
+        }
+    }
+
+    #[test]
+    fn refuses_manifests_it_cant_compare_with() {
+        let tmp = TempDir::new("manifest-refused");
+        let path = tmp.path.join("manifest.json");
+        let manifest = |version: u32, decompress: bool| {
+            format!(
+                r#"{{"version": {}, "root": "x", "decompress": {}, "partial": false, "files": []}}"#,
+                version, decompress
+            )
+        };
+        let options = Options::default();
+        let error = || read(&path, &options).err().unwrap();
+
+        fs::write(&path, manifest(2, false)).unwrap();
+        assert!(error().starts_with("Unsupported manifest version 2"));
+        fs::write(&path, manifest(VERSION, true)).unwrap();
+        assert!(error().contains("was hashed with --decompress"));
+        fs::write(&path, "[]").unwrap();
+        assert!(error().starts_with("Invalid manifest"));
+        fs::write(&path, manifest(VERSION, false)).unwrap();
+        assert!(read(&path, &options).unwrap().files.is_empty());
+    }
+}
@@ -0,0 +1,346 @@
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    archive::is_member,
+    args::{Conflict, Options},
+    cancel,
+    diff::{DiffEntry, Status},
+    progress,
+    sync::{copy_file, same_content, Outcome},
+    Dir, File,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reason {
+    OnlyLeft,
+    OnlyRight,
+    /// Same path and content in both dirs.
+    Identical,
+    /// Same path, different content.
+    Conflict,
+}
+
+/// What merge did with one path of the destination.
+#[derive(Debug, Serialize)]
+pub struct Decision<'a> {
+    /// Path relative to the destination.
+    pub path: String,
+    pub reason: Reason,
+    pub source: &'a str,
+    pub outcome: Outcome,
+    /// For content already stored under another path of the destination,
+    /// the path this one is hard-linked to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stored_as: Option<String>,
+    /// For conflicts, the version that was left out.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dropped: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl<'a> Decision<'a> {
+    fn new(path: &str, reason: Reason, source: &'a File) -> Self {
+        Self {
+            path: path.to_string(),
+            reason,
+            source: &source.path,
+            outcome: Outcome::Planned,
+            stored_as: None,
+            dropped: None,
+            error: None,
+        }
+    }
+}
+
+/// A path next to `path` for the second dir's version of a conflict, like
+/// `notes.right.txt`, that no other file of the merge takes.
+fn suffixed(path: &str, taken: &mut HashSet<String>) -> String {
+    let path = Path::new(path);
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or_default();
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| format!(".{}", e))
+        .unwrap_or_default();
+    let parent = path.parent().unwrap_or(Path::new(""));
+    let mut n = 1;
+    loop {
+        let name = if n == 1 {
+            format!("{}.right{}", stem, ext)
+        } else {
+            format!("{}.right-{}{}", stem, n, ext)
+        };
+        let candidate = parent.join(name).display().to_string();
+        if taken.insert(candidate.clone()) {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// Plans the destination tree from the diff of two dirs: every path of
+/// either dir is kept, identical ones once, and paths with different content
+/// are resolved by the conflict policy. Each content is stored once, under
+/// the first path that has it, and the other paths are hard links to it.
+fn plan<'a>(
+    entries: &[DiffEntry<'a>],
+    left: &'a Dir,
+    right: &'a Dir,
+    policy: Conflict,
+) -> Vec<Decision<'a>> {
+    let files: HashMap<&str, &File> = left
+        .files
+        .iter()
+        .chain(right.files.iter())
+        .filter(|f| !is_member(&f.path))
+        .map(|f| (f.path.as_str(), f))
+        .collect();
+    let mut taken: HashSet<String> = entries
+        .iter()
+        .flat_map(|e| std::iter::once(e.path.clone()).chain(e.old_path.clone()))
+        .collect();
+
+    let mut decisions = Vec::new();
+    for entry in entries {
+        let l = entry.left.and_then(|path| files.get(path).copied());
+        let r = entry.right.and_then(|path| files.get(path).copied());
+        match (entry.status, l, r) {
+            (Status::OnlyLeft, Some(l), _) => {
+                decisions.push(Decision::new(&entry.path, Reason::OnlyLeft, l))
+            }
+            (Status::OnlyRight, _, Some(r)) => {
+                decisions.push(Decision::new(&entry.path, Reason::OnlyRight, r))
+            }
+            (Status::Identical, Some(l), _) => {
+                decisions.push(Decision::new(&entry.path, Reason::Identical, l))
+            }
+            (Status::Moved | Status::Renamed, Some(l), Some(r)) => {
+                let old_path = entry.old_path.as_deref().unwrap_or(&entry.path);
+                decisions.push(Decision::new(old_path, Reason::OnlyLeft, l));
+                decisions.push(Decision::new(&entry.path, Reason::OnlyRight, r));
+            }
+            (Status::Changed, Some(l), Some(r)) => {
+                let left_newer = match (l.modified, r.modified) {
+                    (Some(lm), Some(rm)) => lm >= rm,
+                    (_, rm) => rm.is_none(),
+                };
+                let keep_left = match policy {
+                    Conflict::Newest => left_newer,
+                    Conflict::Left | Conflict::Both => true,
+                    Conflict::Right => false,
+                };
+                let (kept, other) = if keep_left { (l, r) } else { (r, l) };
+                let mut decision = Decision::new(&entry.path, Reason::Conflict, kept);
+                if policy == Conflict::Both {
+                    let path = suffixed(&entry.path, &mut taken);
+                    decisions.push(decision);
+                    decisions.push(Decision::new(&path, Reason::Conflict, r));
+                } else {
+                    decision.dropped = Some(&other.path);
+                    decisions.push(decision);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    let mut by_size: HashMap<u64, Vec<&File>> = HashMap::new();
+    let mut stored: HashMap<&str, String> = HashMap::new();
+    let mut hashes = HashMap::new();
+    for decision in decisions.iter_mut() {
+        let file = files[decision.source];
+        match same_content(file, &by_size, &mut hashes) {
+            Some(existing) => decision.stored_as = Some(stored[existing].clone()),
+            None => {
+                by_size.entry(file.size).or_default().push(file);
+                stored.insert(&file.path, decision.path.clone());
+            }
+        }
+    }
+    decisions
+}
+
+fn link(original: &Path, to: &Path) -> io::Result<()> {
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::hard_link(original, to)
+}
+
+/// Merges `left` and `right` into the destination dir, copying or linking
+/// every file the plan takes unless on a dry run.
+pub fn merge_dirs<'a>(
+    entries: &[DiffEntry<'a>],
+    left: &'a Dir,
+    right: &'a Dir,
+    options: &Options,
+) -> Vec<Decision<'a>> {
+    let mut decisions = plan(entries, left, right, options.conflict);
+    let Some(destination) = options.destination.as_ref() else {
+        return decisions;
+    };
+    let sizes: HashMap<&str, u64> = left
+        .files
+        .iter()
+        .chain(right.files.iter())
+        .map(|f| (f.path.as_str(), f.size))
+        .collect();
+    let bytes_total = decisions
+        .iter()
+        .filter(|d| d.stored_as.is_none())
+        .map(|d| sizes[d.source])
+        .sum();
+    progress::set_phase("merge", Some(bytes_total));
+    if options.dry_run {
+        return decisions;
+    }
+
+    for decision in decisions.iter_mut() {
+        if cancel::stopped() {
+            break;
+        }
+        let to: PathBuf = destination.join(&decision.path);
+        if let Some(stored_as) = decision.stored_as.as_ref() {
+            if link(&destination.join(stored_as), &to).is_ok() {
+                decision.outcome = Outcome::Linked;
+                continue;
+            }
+            // Copy instead, when the first copy failed or links aren't allowed.
+            decision.stored_as = None;
+        }
+        match copy_file(Path::new(decision.source), &to) {
+            Ok(()) => decision.outcome = Outcome::Copied,
+            Err(e) => {
+                if !cancel::stopped() {
+                    eprintln!("Copy err: {}, File {:?}", e, decision.source);
+                    progress::error();
+                }
+                decision.outcome = Outcome::Failed;
+                decision.error = Some(e.to_string());
+            }
+        }
+    }
+    decisions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{diff::diff_dirs, testing::TempDir};
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn suffixes_the_second_version() {
+        let mut taken = HashSet::new();
+        assert_eq!(suffixed("notes.txt", &mut taken), "notes.right.txt");
+        assert_eq!(suffixed("notes.txt", &mut taken), "notes.right-2.txt");
+        assert_eq!(suffixed("a/b.tar.gz", &mut taken), "a/b.tar.right.gz");
+        assert_eq!(suffixed("Makefile", &mut taken), "Makefile.right");
+
+        let mut taken = HashSet::from(["x.right".to_string(), "x.right-2".to_string()]);
+        assert_eq!(suffixed("x", &mut taken), "x.right-3");
+    }
+
+    /// The decisions for a path both dirs hold with different content, the
+    /// right version being the newer one, as (path, source, dropped).
+    fn conflict(policy: Conflict) -> Vec<(String, String, Option<String>)> {
+        let tmp = TempDir::new(&format!("merge-{:?}", policy));
+        let mut left = tmp.dir("left", &[("notes.txt", b"left"), ("same", b"same")]);
+        let mut right = tmp.dir("right", &[("notes.txt", b"right"), ("same", b"same")]);
+        for (dir, secs) in [(&mut left, 1), (&mut right, 2)] {
+            for file in dir.files.iter_mut() {
+                file.modified = Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+            }
+        }
+        let entries = diff_dirs(&left, &right, false);
+        let relative = |path: &str| {
+            Path::new(path)
+                .strip_prefix(&tmp.path)
+                .unwrap()
+                .display()
+                .to_string()
+        };
+        plan(&entries, &left, &right, policy)
+            .into_iter()
+            .filter(|d| d.reason == Reason::Conflict)
+            .map(|d| (d.path, relative(d.source), d.dropped.map(relative)))
+            .collect()
+    }
+
+    fn decision(
+        path: &str,
+        source: &str,
+        dropped: Option<&str>,
+    ) -> (String, String, Option<String>) {
+        (
+            path.to_string(),
+            source.to_string(),
+            dropped.map(str::to_string),
+        )
+    }
+
+    #[test]
+    fn applies_conflict_policies() {
+        assert_eq!(
+            conflict(Conflict::Both),
+            vec![
+                decision("notes.txt", "left/notes.txt", None),
+                decision("notes.right.txt", "right/notes.txt", None),
+            ]
+        );
+        assert_eq!(
+            conflict(Conflict::Left),
+            vec![decision(
+                "notes.txt",
+                "left/notes.txt",
+                Some("right/notes.txt")
+            )]
+        );
+        assert_eq!(
+            conflict(Conflict::Right),
+            vec![decision(
+                "notes.txt",
+                "right/notes.txt",
+                Some("left/notes.txt")
+            )]
+        );
+        assert_eq!(
+            conflict(Conflict::Newest),
+            vec![decision(
+                "notes.txt",
+                "right/notes.txt",
+                Some("left/notes.txt")
+            )]
+        );
+    }
+
+    #[test]
+    fn stores_each_content_once() {
+        let tmp = TempDir::new("merge-links");
+        let left = tmp.dir("left", &[("a", b"copy"), ("same", b"copy")]);
+        let right = tmp.dir("right", &[("sub/b", b"copy"), ("same", b"copy")]);
+        let entries = diff_dirs(&left, &right, false);
+        let stored: Vec<(String, Option<String>)> = plan(&entries, &left, &right, Conflict::Both)
+            .into_iter()
+            .map(|d| (d.path, d.stored_as))
+            .collect();
+        assert_eq!(
+            stored,
+            vec![
+                ("same".to_string(), None),
+                ("a".to_string(), Some("same".to_string())),
+                ("sub/b".to_string(), Some("same".to_string())),
+            ]
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::Path,
+    time::SystemTime,
+};
+
+use crate::{diff::DiffEntry, Dir, Duplicate, File};
+
+/// Ownership, mode bits and extended attributes of a file. Extended
+/// attribute values are kept as SHA-256 digests.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Meta {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub xattrs: BTreeMap<String, String>,
+}
+
+impl Meta {
+    pub fn read(path: &Path, metadata: &fs::Metadata) -> Self {
+        let mut xattrs = BTreeMap::new();
+        if let Ok(names) = xattr::list(path) {
+            for name in names {
+                if let Ok(Some(value)) = xattr::get(path, &name) {
+                    xattrs.insert(
+                        name.to_string_lossy().into_owned(),
+                        format!("{:x}", Sha256::digest(value)),
+                    );
+                }
+            }
+        }
+        Self {
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            xattrs,
+        }
+    }
+}
+
+/// A metadata field in which a copy differs from the first file of its group.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Mismatch<'a> {
+    pub path: &'a str,
+    pub field: String,
+    pub first: String,
+    pub other: String,
+}
+
+fn mtime_differs(a: Option<SystemTime>, b: Option<SystemTime>, tolerance: f64) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            let delta = a.duration_since(b).or_else(|_| b.duration_since(a));
+            delta.is_ok_and(|d| d.as_secs_f64() > tolerance)
+        }
+        (a, b) => a.is_some() != b.is_some(),
+    }
+}
+
+fn secs(time: Option<SystemTime>) -> String {
+    time.and_then(|t| t.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map(|d| format!("{:.3}", d.as_secs_f64()))
+        .unwrap_or_default()
+}
+
+fn compare<'a>(first: &File, other: &'a File, tolerance: f64) -> Vec<Mismatch<'a>> {
+    let mut mismatches = Vec::new();
+    let mut push = |field: &str, a: String, b: String| {
+        mismatches.push(Mismatch {
+            path: &other.path,
+            field: field.to_string(),
+            first: a,
+            other: b,
+        })
+    };
+    if mtime_differs(first.modified, other.modified, tolerance) {
+        push("mtime", secs(first.modified), secs(other.modified));
+    }
+    let (Some(a), Some(b)) = (&first.meta, &other.meta) else {
+        return mismatches;
+    };
+    if a.mode != b.mode {
+        push("mode", format!("{:04o}", a.mode), format!("{:04o}", b.mode));
+    }
+    if a.uid != b.uid {
+        push("uid", a.uid.to_string(), b.uid.to_string());
+    }
+    if a.gid != b.gid {
+        push("gid", a.gid.to_string(), b.gid.to_string());
+    }
+    let names = a.xattrs.keys().chain(b.xattrs.keys());
+    let mut names: Vec<&String> = names.collect();
+    names.sort();
+    names.dedup();
+    for name in names {
+        let (x, y) = (a.xattrs.get(name), b.xattrs.get(name));
+        if x != y {
+            push(
+                &format!("xattr:{}", name),
+                x.cloned().unwrap_or_default(),
+                y.cloned().unwrap_or_default(),
+            );
+        }
+    }
+    mismatches
+}
+
+/// Compares the metadata of every copy in `duplicates` with the first file of
+/// its group. Mtimes within `tolerance` seconds of each other count as equal,
+/// since filesystems keep them at different granularities.
+pub fn add_mismatches<'a>(dirs: &'a [Dir], duplicates: &mut [Duplicate<'a>], tolerance: f64) {
+    let files: HashMap<&str, &File> = dirs
+        .iter()
+        .flat_map(|d| d.files.iter().map(|f| (f.path.as_str(), f)))
+        .collect();
+    for dup in duplicates.iter_mut() {
+        let Some(first) = files.get(dup.first_dir_match) else {
+            continue;
+        };
+        dup.metadata_mismatches = dup
+            .second_dir_match
+            .iter()
+            .filter_map(|path| files.get(path.as_str()))
+            .flat_map(|other| compare(first, other, tolerance))
+            .collect();
+    }
+}
+
+/// Compares the metadata of the right file of every entry of a diff that
+/// pairs two files with the left one, whether moved, changed or identical.
+pub fn add_diff_mismatches<'a>(
+    left: &'a Dir,
+    right: &'a Dir,
+    entries: &mut [DiffEntry<'a>],
+    tolerance: f64,
+) {
+    let files: HashMap<&str, &File> = left
+        .files
+        .iter()
+        .chain(right.files.iter())
+        .map(|f| (f.path.as_str(), f))
+        .collect();
+    for entry in entries.iter_mut() {
+        let (Some(l), Some(r)) = (entry.left, entry.right) else {
+            continue;
+        };
+        if let (Some(first), Some(other)) = (files.get(l), files.get(r)) {
+            entry.metadata_mismatches = compare(first, other, tolerance);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{args::Scope, testing::TempDir};
+    use std::{fs::Permissions, os::unix::fs::PermissionsExt, time::Duration};
+
+    fn at(secs: f64) -> Option<SystemTime> {
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs_f64(secs))
+    }
+
+    fn fields(mismatches: &[Mismatch]) -> Vec<String> {
+        mismatches.iter().map(|m| m.field.clone()).collect()
+    }
+
+    #[test]
+    fn allows_mtimes_within_the_tolerance() {
+        assert!(!mtime_differs(at(100.0), at(100.0), 0.0));
+        assert!(mtime_differs(at(100.0), at(101.0), 0.0));
+        assert!(!mtime_differs(at(100.0), at(101.0), 2.0));
+        assert!(!mtime_differs(at(101.0), at(100.0), 1.0));
+        assert!(mtime_differs(at(100.0), at(102.5), 2.0));
+        assert!(mtime_differs(at(100.0), None, 2.0));
+        assert!(!mtime_differs(None, None, 0.0));
+    }
+
+    #[test]
+    fn reports_each_differing_field() {
+        let tmp = TempDir::new("metadata-fields");
+        let meta = |mode, uid, xattrs: &[(&str, &str)]| Meta {
+            mode,
+            uid,
+            gid: 100,
+            xattrs: xattrs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        };
+        let mut first = tmp.file("a", b"x");
+        first.modified = at(10.0);
+        first.meta = Some(meta(0o644, 1000, &[("user.tag", "1")]));
+        let mut other = tmp.file("b", b"x");
+        other.modified = at(10.5);
+        other.meta = Some(meta(0o600, 1000, &[("user.tag", "2"), ("user.new", "3")]));
+
+        let mismatches = compare(&first, &other, 1.0);
+        assert_eq!(
+            fields(&mismatches),
+            ["mode", "xattr:user.new", "xattr:user.tag"]
+        );
+        assert_eq!(mismatches[0].path, other.path);
+        assert_eq!(
+            (&*mismatches[0].first, &*mismatches[0].other),
+            ("0644", "0600")
+        );
+        assert_eq!((&*mismatches[1].first, &*mismatches[1].other), ("", "3"));
+
+        other.meta = first.meta.clone();
+        assert_eq!(fields(&compare(&first, &other, 0.0)), ["mtime"]);
+        other.meta = None;
+        assert!(compare(&first, &other, 1.0).is_empty());
+    }
+
+    #[test]
+    fn compares_copies_with_the_first_file_of_their_group() {
+        let tmp = TempDir::new("metadata-groups");
+        let mut dir = tmp.dir("root", &[("a", b"x"), ("b", b"x"), ("c", b"x")]);
+        fs::set_permissions(&dir.files[2].path, Permissions::from_mode(0o600)).unwrap();
+        for file in dir.files.iter_mut() {
+            let metadata = fs::metadata(&file.path).unwrap();
+            file.meta = Some(Meta::read(Path::new(&file.path), &metadata));
+        }
+        let dirs = vec![dir];
+        let (a, b, c) = (&dirs[0].files[0], &dirs[0].files[1], &dirs[0].files[2]);
+        let mut duplicates = vec![Duplicate {
+            file_name: &a.name,
+            first_dir_match: &a.path,
+            second_dir_match: vec![b.path.clone(), c.path.clone()],
+            scope: Scope::IntraRoot,
+            roots: vec![0; 3],
+            decompressed: Vec::new(),
+            metadata_mismatches: Vec::new(),
+        }];
+
+        add_mismatches(&dirs, &mut duplicates, 0.0);
+        let mismatches = &duplicates[0].metadata_mismatches;
+        assert_eq!(fields(mismatches), ["mode"]);
+        assert_eq!(mismatches[0].path, c.path);
+        assert_eq!(mismatches[0].other, "0600");
+    }
+}
@@ -0,0 +1,177 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fs,
+    hash::{Hash, Hasher},
+};
+
+use crate::{archive::is_member, cancel, progress, similar::SimilarPair, Dir};
+
+const SHINGLE_WORDS: usize = 3;
+const BANDS: usize = 32;
+const ROWS: usize = 4;
+const PERMUTATIONS: usize = BANDS * ROWS;
+const MAX_TEXT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Reads `path` as text. Files with invalid UTF-8 or NUL bytes are taken as
+/// binary and skipped.
+fn read_text(path: &str) -> Option<String> {
+    let bytes = fs::read(path)
+        .map_err(|e| {
+            eprintln!("Read err: {}, File {:?}", e, path);
+            progress::error();
+        })
+        .ok()?;
+    if bytes.contains(&0) {
+        return None;
+    }
+    String::from_utf8(bytes).ok()
+}
+
+fn shingles(text: &str) -> HashSet<u64> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let hash = |words: &[&str]| {
+        let mut hasher = DefaultHasher::new();
+        words.hash(&mut hasher);
+        hasher.finish()
+    };
+    if words.len() < SHINGLE_WORDS {
+        return words.chunks(words.len().max(1)).map(hash).collect();
+    }
+    words.windows(SHINGLE_WORDS).map(hash).collect()
+}
+
+fn mix(mut x: u64) -> u64 {
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xff51afd7ed558ccd);
+    x ^= x >> 33;
+    x = x.wrapping_mul(0xc4ceb9fe1a85ec53);
+    x ^ (x >> 33)
+}
+
+/// Keeps the smallest value of each of the seeded hash permutations.
+fn signature(shingles: &HashSet<u64>) -> Vec<u64> {
+    (0..PERMUTATIONS as u64)
+        .map(|seed| {
+            let seed = mix(seed + 1);
+            shingles
+                .iter()
+                .map(|s| mix(s ^ seed))
+                .min()
+                .unwrap_or(u64::MAX)
+        })
+        .collect()
+}
+
+fn estimate_jaccard(a: &[u64], b: &[u64]) -> f64 {
+    let equal = a.iter().zip(b).filter(|(x, y)| x == y).count();
+    equal as f64 / PERMUTATIONS as f64
+}
+
+/// Pairs text files of all dirs whose estimated shingle similarity is at least
+/// `threshold`. Candidates come from banding the MinHash signatures, so pairs
+/// are only scored when they agree on a whole band.
+pub fn find_similar_texts(dirs: &[Dir], threshold: f64) -> Vec<SimilarPair<'_>> {
+    let signatures: Vec<(&str, Vec<u64>)> = dirs
+        .iter()
+        .flat_map(|d| d.files.iter())
+        .filter(|f| f.size > 0 && f.size <= MAX_TEXT_SIZE && !is_member(&f.path))
+        .take_while(|_| !cancel::stopped())
+        .filter_map(|f| {
+            let text = read_text(&f.path)?;
+            let shingles = shingles(&text);
+            if shingles.is_empty() {
+                return None;
+            }
+            Some((f.path.as_str(), signature(&shingles)))
+        })
+        .collect();
+
+    let mut candidates: HashSet<(usize, usize)> = HashSet::new();
+    for band in 0..BANDS {
+        let mut buckets: HashMap<&[u64], Vec<usize>> = HashMap::new();
+        for (idx, (_, sig)) in signatures.iter().enumerate() {
+            buckets
+                .entry(&sig[band * ROWS..(band + 1) * ROWS])
+                .or_default()
+                .push(idx);
+        }
+        for bucket in buckets.values() {
+            for (i, a) in bucket.iter().enumerate() {
+                for b in bucket[i + 1..].iter() {
+                    candidates.insert((*a, *b));
+                }
+            }
+        }
+    }
+
+    let mut candidates: Vec<(usize, usize)> = candidates.into_iter().collect();
+    candidates.sort();
+    candidates
+        .into_iter()
+        .filter_map(|(a, b)| {
+            let similarity = estimate_jaccard(&signatures[a].1, &signatures[b].1);
+            (similarity >= threshold).then(|| SimilarPair {
+                first: signatures[a].0,
+                second: signatures[b].0,
+                similarity,
+                distance: None,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn text(words: usize, seed: usize) -> String {
+        (0..words)
+            .map(|i| format!("w{}", (i * 7 + seed) % 1000))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn estimates_the_shingle_similarity() {
+        let a = signature(&shingles(&text(400, 0)));
+        let edited = format!("{} inserted line {}", text(200, 0), &text(400, 0)[1000..]);
+        let b = signature(&shingles(&edited));
+        let c = signature(&shingles(&text(400, 3)));
+        assert_eq!(estimate_jaccard(&a, &a), 1.0);
+        assert!(estimate_jaccard(&a, &b) > 0.7);
+        assert!(estimate_jaccard(&a, &c) < 0.1);
+    }
+
+    #[test]
+    fn shingles_short_texts_whole() {
+        assert_eq!(shingles("").len(), 0);
+        assert_eq!(shingles("two words").len(), 1);
+        assert_eq!(shingles("one two three four").len(), 2);
+    }
+
+    #[test]
+    fn pairs_similar_texts_above_the_threshold() {
+        let tmp = TempDir::new("minhash-texts");
+        let original = text(400, 0);
+        let edited = original.replacen("w7 ", "w7 changed ", 1);
+        let dirs = vec![
+            tmp.dir(
+                "left",
+                &[
+                    ("doc.txt", original.as_bytes()),
+                    ("other.txt", text(400, 3).as_bytes()),
+                    ("binary.bin", b"w0 w7 w14\0"),
+                ],
+            ),
+            tmp.dir("right", &[("doc.txt", edited.as_bytes())]),
+        ];
+
+        let pairs = find_similar_texts(&dirs, 0.7);
+        assert_eq!(pairs.len(), 1);
+        assert!(pairs[0].first.ends_with("left/doc.txt"));
+        assert!(pairs[0].second.ends_with("right/doc.txt"));
+        assert!(pairs[0].similarity >= 0.7 && pairs[0].similarity < 1.0);
+        assert!(find_similar_texts(&dirs, 1.0).is_empty());
+    }
+}
@@ -0,0 +1,107 @@
+use serde::Serialize;
+use std::path::Path;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::File;
+
+/// How file names are compared when matching by name.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct NameMatch {
+    pub ignore_case: bool,
+    /// Compare NFC forms, so names written in NFD (as macOS does) still match.
+    pub normalize: bool,
+    /// Compare names without their extension.
+    pub stem: bool,
+    /// Names only match when the sizes are equal too.
+    pub with_size: bool,
+}
+
+pub fn name_key(file: &File, name_match: &NameMatch) -> String {
+    let mut name = if name_match.normalize {
+        file.name.nfc().collect()
+    } else {
+        file.name.clone()
+    };
+    if name_match.stem {
+        if let Some(stem) = Path::new(&name).file_stem().and_then(|s| s.to_str()) {
+            name = stem.to_string();
+        }
+    }
+    if name_match.ignore_case {
+        name = name.to_lowercase();
+    }
+    if name_match.with_size {
+        name = format!("{}\0{}", name, file.size);
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::file;
+
+    fn key(name: &str, size: u64, name_match: NameMatch) -> String {
+        name_key(&file(&Path::new("dir").join(name), size), &name_match)
+    }
+
+    #[test]
+    fn exact_names_by_default() {
+        assert_eq!(key("Report.PDF", 1, NameMatch::default()), "Report.PDF");
+        assert_ne!(
+            key("report.pdf", 1, NameMatch::default()),
+            key("Report.PDF", 1, NameMatch::default())
+        );
+    }
+
+    #[test]
+    fn ignores_case() {
+        let name_match = NameMatch {
+            ignore_case: true,
+            ..Default::default()
+        };
+        assert_eq!(key("Report.PDF", 1, name_match), "report.pdf");
+    }
+
+    #[test]
+    fn normalizes_to_nfc() {
+        let name_match = NameMatch {
+            normalize: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            key("cafe\u{301}.txt", 1, name_match.clone()),
+            key("caf\u{e9}.txt", 1, name_match)
+        );
+        assert_ne!(
+            key("cafe\u{301}.txt", 1, NameMatch::default()),
+            key("caf\u{e9}.txt", 1, NameMatch::default())
+        );
+    }
+
+    #[test]
+    fn drops_the_extension_with_stem() {
+        let name_match = NameMatch {
+            stem: true,
+            ..Default::default()
+        };
+        assert_eq!(key("song.flac", 1, name_match.clone()), "song");
+        assert_eq!(key("archive.tar.gz", 1, name_match.clone()), "archive.tar");
+        assert_eq!(key(".bashrc", 1, name_match), ".bashrc");
+    }
+
+    #[test]
+    fn adds_the_size() {
+        let name_match = NameMatch {
+            ignore_case: true,
+            stem: true,
+            with_size: true,
+            ..Default::default()
+        };
+        assert_eq!(key("A.txt", 10, name_match.clone()), "a\u{0}10");
+        assert_ne!(
+            key("a.txt", 10, name_match.clone()),
+            key("a.txt", 11, name_match)
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use image::{
+    imageops::{self, FilterType},
+    ImageError,
+};
+use std::{f64::consts::PI, path::Path};
+
+use crate::{archive::is_member, cancel, progress, similar::SimilarPair, Dir};
+
+const IMAGE_EXTENSIONS: [&str; 5] = ["png", "jpg", "jpeg", "bmp", "gif"];
+const SIDE: usize = 32;
+const LOW_FREQ: usize = 8;
+pub const HASH_BITS: u32 = (LOW_FREQ * LOW_FREQ) as u32;
+
+pub fn is_image(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| {
+            IMAGE_EXTENSIONS
+                .iter()
+                .any(|known| ext.eq_ignore_ascii_case(known))
+        })
+}
+
+/// DCT based hash: the image is shrunk to 32x32 grey pixels and each bit tells
+/// whether one of the 8x8 lowest frequencies is above their median.
+pub fn perceptual_hash(path: &Path) -> Result<u64, ImageError> {
+    let img = image::open(path)?.to_luma8();
+    let small = imageops::resize(&img, SIDE as u32, SIDE as u32, FilterType::Triangle);
+    let pixels: Vec<f64> = small.pixels().map(|p| p.0[0] as f64).collect();
+
+    let cos: Vec<Vec<f64>> = (0..LOW_FREQ)
+        .map(|u| {
+            (0..SIDE)
+                .map(|x| ((2 * x + 1) as f64 * u as f64 * PI / (2 * SIDE) as f64).cos())
+                .collect()
+        })
+        .collect();
+
+    let mut rows = vec![[0f64; LOW_FREQ]; SIDE];
+    for (y, row) in rows.iter_mut().enumerate() {
+        for (u, value) in row.iter_mut().enumerate() {
+            *value = (0..SIDE).map(|x| pixels[y * SIDE + x] * cos[u][x]).sum();
+        }
+    }
+    let mut coeffs = Vec::with_capacity(LOW_FREQ * LOW_FREQ);
+    for cos_v in cos.iter() {
+        for u in 0..LOW_FREQ {
+            coeffs.push(
+                rows.iter()
+                    .zip(cos_v)
+                    .map(|(row, c)| row[u] * c)
+                    .sum::<f64>(),
+            );
+        }
+    }
+
+    // the DC term only carries the average brightness
+    let mut ac = coeffs[1..].to_vec();
+    ac.sort_by(|a, b| a.total_cmp(b));
+    let median = ac[ac.len() / 2];
+
+    Ok(coeffs.iter().enumerate().fold(
+        0u64,
+        |hash, (i, c)| if *c > median { hash | 1 << i } else { hash },
+    ))
+}
+
+/// Hashes in a BK-tree: the children of a node are keyed by their Hamming
+/// distance to it, so that by the triangle inequality a search within `max`
+/// bits of a hash at distance `d` only goes down children from `d - max` to
+/// `d + max`. Equal hashes share a node.
+#[derive(Default)]
+struct BkTree {
+    nodes: Vec<BkNode>,
+}
+
+struct BkNode {
+    hash: u64,
+    items: Vec<usize>,
+    children: Vec<(u32, usize)>,
+}
+
+impl BkTree {
+    fn insert(&mut self, hash: u64, item: usize) {
+        let mut idx = 0;
+        if self.nodes.is_empty() {
+            self.nodes.push(BkNode {
+                hash,
+                items: vec![item],
+                children: Vec::new(),
+            });
+            return;
+        }
+        loop {
+            let distance = (self.nodes[idx].hash ^ hash).count_ones();
+            if distance == 0 {
+                self.nodes[idx].items.push(item);
+                return;
+            }
+            let child = self.nodes[idx]
+                .children
+                .iter()
+                .find(|(d, _)| *d == distance)
+                .map(|(_, child)| *child);
+            match child {
+                Some(child) => idx = child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(BkNode {
+                        hash,
+                        items: vec![item],
+                        children: Vec::new(),
+                    });
+                    self.nodes[idx].children.push((distance, child));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Calls `found` with every item within `max` bits of `hash`.
+    fn search(&self, hash: u64, max: u32, mut found: impl FnMut(usize, u32)) {
+        if self.nodes.is_empty() {
+            return;
+        }
+        let mut stack = vec![0];
+        while let Some(idx) = stack.pop() {
+            let node = &self.nodes[idx];
+            let distance = (node.hash ^ hash).count_ones();
+            if distance <= max {
+                for item in node.items.iter() {
+                    found(*item, distance);
+                }
+            }
+            let range = distance.saturating_sub(max)..=distance + max;
+            stack.extend(
+                node.children
+                    .iter()
+                    .filter(|(d, _)| range.contains(d))
+                    .map(|(_, child)| *child),
+            );
+        }
+    }
+}
+
+/// Pairs images of all dirs whose hashes differ in at most `max_distance` bits.
+pub fn find_similar_images(dirs: &[Dir], max_distance: u32) -> Vec<SimilarPair<'_>> {
+    let hashes: Vec<(&str, u64)> = dirs
+        .iter()
+        .flat_map(|d| d.files.iter())
+        .filter(|f| is_image(&f.path) && !is_member(&f.path))
+        .take_while(|_| !cancel::stopped())
+        .filter_map(|f| match perceptual_hash(Path::new(&f.path)) {
+            Ok(hash) => Some((f.path.as_str(), hash)),
+            Err(ImageError::IoError(e)) => {
+                eprintln!("Image err: {}, File {:?}", e, f.path);
+                progress::error();
+                None
+            }
+            // Files named like images that don't decode as one are left
+            // out, like any other file that isn't an image.
+            Err(_) => None,
+        })
+        .collect();
+
+    // Each image is looked up among the ones before it, then added.
+    let mut tree = BkTree::default();
+    let mut matches: Vec<(usize, usize, u32)> = Vec::new();
+    for (i, (_, hash)) in hashes.iter().enumerate() {
+        tree.search(*hash, max_distance, |j, distance| {
+            matches.push((j, i, distance))
+        });
+        tree.insert(*hash, i);
+    }
+    matches.sort_unstable();
+    matches
+        .into_iter()
+        .map(|(j, i, distance)| SimilarPair {
+            first: hashes[j].0,
+            second: hashes[i].0,
+            similarity: 1.0 - distance as f64 / HASH_BITS as f64,
+            distance: Some(distance),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use image::{GrayImage, Luma};
+
+    fn hashes() -> Vec<u64> {
+        let mut state: u64 = 0x2545f4914f6cdd1d;
+        let mut hashes: Vec<u64> = (0..300)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state
+            })
+            .collect();
+        // Near copies, one to a few bits off, and an exact one.
+        for (i, bits) in [(0, 1u64), (1, 0b101), (2, 0xff), (3, 0)] {
+            hashes.push(hashes[i] ^ bits);
+        }
+        hashes
+    }
+
+    #[test]
+    fn bk_tree_finds_exactly_the_hashes_within_the_distance() {
+        let hashes = hashes();
+        let mut tree = BkTree::default();
+        for (i, hash) in hashes.iter().enumerate() {
+            tree.insert(*hash, i);
+        }
+        for max in [0, 1, 2, 8, 24, 64] {
+            for (i, hash) in hashes.iter().enumerate().step_by(7) {
+                let mut found = Vec::new();
+                tree.search(*hash, max, |item, distance| found.push((item, distance)));
+                found.sort();
+                let expected: Vec<(usize, u32)> = hashes
+                    .iter()
+                    .map(|other| (other ^ hash).count_ones())
+                    .enumerate()
+                    .filter(|(_, distance)| *distance <= max)
+                    .collect();
+                assert_eq!(found, expected, "hash {} within {} bits", i, max);
+            }
+        }
+    }
+
+    #[test]
+    fn pairs_similar_images_and_skips_undecodable_ones() {
+        let tmp = TempDir::new("phash-images");
+        let gradient = |shift: u32| {
+            GrayImage::from_fn(64, 64, |x, y| Luma([((x * 3 + y + shift) % 256) as u8]))
+        };
+        let checks = GrayImage::from_fn(64, 64, |x, y| {
+            Luma([if (x / 8 + y / 8) % 2 == 0 { 0 } else { 255 }])
+        });
+        let mut dir = tmp.dir("images", &[("broken.png", b"not an image")]);
+        for (name, image) in [
+            ("a.png", gradient(0)),
+            ("b.png", gradient(2)),
+            ("c.png", checks),
+        ] {
+            let path = tmp.path.join("images").join(name);
+            image.save(&path).unwrap();
+            dir.files.push(crate::testing::file(&path, 0));
+        }
+        let dirs = vec![dir];
+
+        let name = |path: &str| path.rsplit('/').next().unwrap().to_string();
+        let pairs: Vec<(String, String)> = find_similar_images(&dirs, 10)
+            .iter()
+            .map(|p| (name(p.first), name(p.second)))
+            .collect();
+        assert_eq!(pairs, vec![("a.png".to_string(), "b.png".to_string())]);
+    }
+}
@@ -0,0 +1,208 @@
+use serde::Serialize;
+use std::{
+    fs,
+    io::{self, IsTerminal, Write},
+    os::fd::BorrowedFd,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const TICK: Duration = Duration::from_millis(200);
+
+static FILES_WALKED: AtomicU64 = AtomicU64::new(0);
+static BYTES_HASHED: AtomicU64 = AtomicU64::new(0);
+static ERRORS: AtomicU64 = AtomicU64::new(0);
+static REPORTER: Mutex<Option<Reporter>> = Mutex::new(None);
+
+struct Reporter {
+    started: Instant,
+    last_tick: Instant,
+    terminal: bool,
+    json: Option<fs::File>,
+    phase: &'static str,
+    phase_started: Instant,
+    phase_bytes: u64,
+    bytes_total: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Event {
+    event: &'static str,
+    phase: &'static str,
+    files_walked: u64,
+    bytes_hashed: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_total: Option<u64>,
+    bytes_per_sec: u64,
+    errors: u64,
+    elapsed_ms: u128,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eta_ms: Option<u128>,
+}
+
+impl Reporter {
+    fn event(&self, event: &'static str) -> Event {
+        let bytes_hashed = BYTES_HASHED.load(Ordering::Relaxed);
+        let phase_hashed = bytes_hashed - self.phase_bytes;
+        let phase_secs = self.phase_started.elapsed().as_secs_f64();
+        let bytes_per_sec = if phase_secs > 0.0 {
+            (phase_hashed as f64 / phase_secs) as u64
+        } else {
+            0
+        };
+        let eta_ms = match self.bytes_total {
+            Some(total) if bytes_per_sec > 0 => {
+                Some(total.saturating_sub(phase_hashed) as u128 * 1000 / bytes_per_sec as u128)
+            }
+            _ => None,
+        };
+        Event {
+            event,
+            phase: self.phase,
+            files_walked: FILES_WALKED.load(Ordering::Relaxed),
+            bytes_hashed,
+            bytes_total: self.bytes_total,
+            bytes_per_sec,
+            errors: ERRORS.load(Ordering::Relaxed),
+            elapsed_ms: self.started.elapsed().as_millis(),
+            eta_ms,
+        }
+    }
+
+    fn emit(&mut self, event: &'static str) {
+        let event = self.event(event);
+        if self.terminal {
+            let mut line = format!(
+                "[{}] {} files walked, {} hashed ({}/s)",
+                event.phase,
+                event.files_walked,
+                human_bytes(event.bytes_hashed),
+                human_bytes(event.bytes_per_sec)
+            );
+            if let Some(eta_ms) = event.eta_ms {
+                let secs = eta_ms / 1000;
+                line.push_str(&format!(", ETA {}:{:02}", secs / 60, secs % 60));
+            }
+            eprint!("\r\x1b[2K{}", line);
+        }
+        if let Some(json) = self.json.as_mut() {
+            if let Ok(mut line) = serde_json::to_vec(&event) {
+                line.push(b'\n');
+                let _ = json.write_all(&line);
+            }
+        }
+        self.last_tick = Instant::now();
+    }
+}
+
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Opens a duplicate of `fd`, so that the caller's fd stays open and its
+/// number can't be taken by a file opened later.
+fn dup_fd(fd: i32) -> Result<fs::File, String> {
+    if fd < 0 {
+        return Err(format!("Invalid progress fd: {}", fd));
+    }
+    // SAFETY: the fd is only borrowed to duplicate it, and a closed one
+    // makes the duplication fail instead of being used.
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    let mut file = borrowed.try_clone_to_owned().map(fs::File::from);
+    // An empty write still fails on an fd not open for writing.
+    if let Ok(file) = file.as_mut() {
+        if let Err(e) = file.write(&[]) {
+            return Err(format!("Can't write progress to fd {}: {}", fd, e));
+        }
+    }
+    file.map_err(|e| format!("Can't write progress to fd {}: {}", fd, e))
+}
+
+/// Starts reporting. The live line is only drawn when `live` is set and
+/// stderr is a terminal, JSON lines go to `json_fd` when one is given.
+pub fn init(json_fd: Option<i32>, live: bool) -> Result<(), String> {
+    let json = json_fd.map(dup_fd).transpose()?;
+    let terminal = live && io::stderr().is_terminal();
+    if !terminal && json.is_none() {
+        return Ok(());
+    }
+    let now = Instant::now();
+    let mut reporter = Reporter {
+        started: now,
+        last_tick: now,
+        terminal,
+        json,
+        phase: "walk",
+        phase_started: now,
+        phase_bytes: 0,
+        bytes_total: None,
+    };
+    reporter.emit("start");
+    *REPORTER.lock().unwrap() = Some(reporter);
+    Ok(())
+}
+
+/// Moves on to the next phase, with `bytes_total` to hash in it when known.
+pub fn set_phase(phase: &'static str, bytes_total: Option<u64>) {
+    if let Some(reporter) = REPORTER.lock().unwrap().as_mut() {
+        reporter.phase = phase;
+        reporter.phase_started = Instant::now();
+        reporter.phase_bytes = BYTES_HASHED.load(Ordering::Relaxed);
+        reporter.bytes_total = bytes_total;
+        reporter.emit("phase");
+    }
+}
+
+fn tick() {
+    if let Some(reporter) = REPORTER.lock().unwrap().as_mut() {
+        if reporter.last_tick.elapsed() >= TICK {
+            reporter.emit("progress");
+        }
+    }
+}
+
+pub fn file_walked() {
+    FILES_WALKED.fetch_add(1, Ordering::Relaxed);
+    tick();
+}
+
+pub fn bytes_hashed(bytes: u64) {
+    BYTES_HASHED.fetch_add(bytes, Ordering::Relaxed);
+    tick();
+}
+
+/// Counts a file or dir that couldn't be read, leaving the results partial.
+pub fn error() {
+    ERRORS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn walked() -> u64 {
+    FILES_WALKED.load(Ordering::Relaxed)
+}
+
+pub fn hashed() -> u64 {
+    BYTES_HASHED.load(Ordering::Relaxed)
+}
+
+pub fn errors() -> u64 {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+pub fn finish() {
+    if let Some(mut reporter) = REPORTER.lock().unwrap().take() {
+        reporter.emit("finish");
+        if reporter.terminal {
+            eprintln!();
+        }
+    }
+}
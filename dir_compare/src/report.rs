@@ -0,0 +1,98 @@
+use serde::Serialize;
+use std::{
+    fs,
+    io::{BufWriter, Write},
+};
+
+use crate::{
+    args::Options, cancel, chunks::ChunkStats, diff::DiffEntry, merge::Decision, progress,
+    similar::SimilarGroup, spill::Groups, stats::Stats, subtree::DirDuplicate, sync::Transfer,
+    verify::Check, Duplicate,
+};
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+/// Tells whether the results miss something, from a stop or unread files.
+pub fn is_partial() -> bool {
+    cancel::reason().is_some() || progress::errors() > 0
+}
+
+/// Version of the report layout. Version 1 was a bare array of duplicate
+/// groups, before the report became an object.
+const VERSION: u32 = 2;
+
+/// What a run writes out. A stopped run writes the same report, marked as
+/// partial, covering only what was read before the stop. So is a run that
+/// couldn't read some of the files.
+#[derive(Debug, Serialize)]
+pub struct Report<'a> {
+    pub version: u32,
+    pub options: &'a Options,
+    pub partial: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub errors: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<Stats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duplicates: Option<Vec<Duplicate<'a>>>,
+    /// Duplicates found with the file index on disk, streamed into the file.
+    #[serde(rename = "duplicates", skip_serializing_if = "Option::is_none")]
+    pub spilled: Option<Groups>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dir_duplicates: Vec<DirDuplicate<'a>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub similar: Vec<SimilarGroup<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<Vec<DiffEntry<'a>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verify: Option<Vec<Check>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub chunks: Option<ChunkStats>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sync: Option<Vec<Transfer<'a>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub merge: Option<Vec<Decision<'a>>>,
+}
+
+impl<'a> Report<'a> {
+    pub fn new(options: &'a Options) -> Self {
+        let stop_reason = cancel::reason();
+        let errors = progress::errors();
+        Self {
+            version: VERSION,
+            options,
+            partial: is_partial(),
+            stop_reason,
+            errors,
+            stats: None,
+            duplicates: None,
+            spilled: None,
+            dir_duplicates: Vec::new(),
+            similar: Vec::new(),
+            diff: None,
+            verify: None,
+            chunks: None,
+            sync: None,
+            merge: None,
+        }
+    }
+
+    pub fn write(&self, output_file: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(reason) = self.stop_reason {
+            eprintln!("Scan stopped early ({}), writing a partial report", reason);
+        } else if self.errors > 0 {
+            eprintln!(
+                "{} files or dirs couldn't be read, writing a partial report",
+                self.errors
+            );
+        }
+        let mut out = BufWriter::new(fs::File::create(output_file)?);
+        serde_json::to_writer_pretty(&mut out, self)?;
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,450 @@
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, List, ListItem, ListState, Paragraph},
+    DefaultTerminal, Frame,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, BufWriter, IsTerminal, Read, Write},
+    path::Path,
+};
+
+use crate::{archive::is_member, args::MatchBy, progress::human_bytes};
+
+/// Bytes of a file read for its preview.
+const PREVIEW_BYTES: u64 = 64 * 1024;
+
+/// The part of a saved report review reads, which plain and spilled
+/// duplicate reports both have. Reports of version 1 were a bare array of
+/// the groups, which were always matched by name.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Saved {
+    Versioned {
+        options: SavedOptions,
+        #[serde(default)]
+        duplicates: Vec<SavedGroup>,
+    },
+    Bare(Vec<SavedGroup>),
+}
+
+#[derive(Deserialize)]
+struct SavedOptions {
+    match_by: MatchBy,
+}
+
+#[derive(Deserialize)]
+struct SavedGroup {
+    file_name: String,
+    first_dir_match: String,
+    second_dir_match: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Mark {
+    Keep,
+    Delete,
+    /// Replace with a hard link to the first file kept in the group.
+    Link,
+}
+
+struct Member {
+    path: String,
+    size: Option<u64>,
+    mark: Option<Mark>,
+}
+
+struct Group {
+    file_name: String,
+    members: Vec<Member>,
+    size: u64,
+    /// Bytes the copies take up, known only when the files are identical.
+    wasted: Option<u64>,
+}
+
+/// What to do with the files of one group. Files left out are left alone.
+#[derive(Debug, Serialize)]
+struct PlanGroup<'a> {
+    keep: Vec<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    delete: Vec<&'a str>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    link: Vec<&'a str>,
+}
+
+/// The decisions of a review, for a later step to carry out.
+#[derive(Debug, Serialize)]
+struct Plan<'a> {
+    report: String,
+    groups: Vec<PlanGroup<'a>>,
+}
+
+#[derive(PartialEq)]
+enum Focus {
+    Groups,
+    Files,
+}
+
+struct Review {
+    report: String,
+    groups: Vec<Group>,
+    /// The groups were matched on content, so their files are identical.
+    identical: bool,
+    /// A delete of a file that may differ from the rest was asked for once.
+    confirming: bool,
+    group_state: ListState,
+    file_state: ListState,
+    focus: Focus,
+    preview: Option<(String, String)>,
+    status: String,
+    unsaved: bool,
+}
+
+/// Reads the groups of the report at `path`, and whether they were matched on
+/// content.
+fn load(path: &Path) -> Result<(Vec<Group>, bool), String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't read report: {}. Error: {}", path.display(), e))?;
+    let saved: Saved = serde_json::from_str(&text)
+        .map_err(|e| format!("Invalid report: {}. Error: {}", path.display(), e))?;
+    let (duplicates, identical) = match saved {
+        Saved::Versioned {
+            options,
+            duplicates,
+        } => (duplicates, options.match_by == MatchBy::Content),
+        Saved::Bare(duplicates) => (duplicates, false),
+    };
+    let mut groups: Vec<Group> = duplicates
+        .into_iter()
+        .map(|group| {
+            let members: Vec<Member> = std::iter::once(group.first_dir_match)
+                .chain(group.second_dir_match)
+                .map(|path| Member {
+                    size: fs::symlink_metadata(&path).ok().map(|m| m.len()),
+                    path,
+                    mark: None,
+                })
+                .collect();
+            let size = members.iter().filter_map(|m| m.size).max().unwrap_or(0);
+            Group {
+                file_name: group.file_name,
+                size,
+                wasted: identical.then(|| size * (members.len() as u64 - 1)),
+                members,
+            }
+        })
+        .collect();
+    if groups.is_empty() {
+        return Err(format!("No duplicate groups in report: {}", path.display()));
+    }
+    groups.sort_by(|a, b| {
+        (b.wasted, b.size)
+            .cmp(&(a.wasted, a.size))
+            .then(a.file_name.cmp(&b.file_name))
+    });
+    Ok((groups, identical))
+}
+
+/// The start of the file at `path` as text, or a line on what it is when it
+/// isn't text.
+fn preview(path: &str) -> String {
+    if is_member(path) {
+        return "Inside an archive, no preview".into();
+    }
+    if let Ok((width, height)) = image::image_dimensions(path) {
+        return format!("Image, {}x{}", width, height);
+    }
+    let mut bytes = Vec::new();
+    let read =
+        fs::File::open(path).and_then(|file| file.take(PREVIEW_BYTES).read_to_end(&mut bytes));
+    if let Err(e) = read {
+        return format!("Couldn't read file: {}", e);
+    }
+    if bytes.contains(&0) {
+        return format!("Binary, {} bytes read", bytes.len());
+    }
+    match std::str::from_utf8(&bytes) {
+        Ok(text) => text.to_string(),
+        // The read may have cut a character in two at the end.
+        Err(e) if e.error_len().is_none() => {
+            String::from_utf8_lossy(&bytes[..e.valid_up_to()]).into_owned()
+        }
+        Err(_) => format!("Binary, {} bytes read", bytes.len()),
+    }
+}
+
+impl Review {
+    fn group(&self) -> &Group {
+        &self.groups[self.group_state.selected().unwrap_or(0)]
+    }
+
+    fn member(&self) -> &Member {
+        &self.group().members[self.file_state.selected().unwrap_or(0)]
+    }
+
+    fn step(&mut self, delta: isize) {
+        let (state, len) = match self.focus {
+            Focus::Groups => (&mut self.group_state, self.groups.len()),
+            Focus::Files => {
+                let len = self.groups[self.group_state.selected().unwrap_or(0)]
+                    .members
+                    .len();
+                (&mut self.file_state, len)
+            }
+        };
+        let selected = state.selected().unwrap_or(0) as isize + delta;
+        state.select(Some(selected.clamp(0, len as isize - 1) as usize));
+        if self.focus == Focus::Groups {
+            self.file_state.select(Some(0));
+        }
+    }
+
+    fn mark(&mut self, mark: Option<Mark>) {
+        if mark.is_some_and(|mark| mark != Mark::Keep) && is_member(&self.member().path) {
+            self.status = "Files inside archives can only be kept".into();
+            return;
+        }
+        // Files matched by name or similarity may hold different bytes.
+        if !self.identical && mark == Some(Mark::Link) {
+            self.status = "Files may differ, link only works for content matches".into();
+            return;
+        }
+        if !self.identical && mark == Some(Mark::Delete) && !self.confirming {
+            self.confirming = true;
+            self.status = "Files may differ, press d again to delete anyway".into();
+            return;
+        }
+        self.confirming = false;
+        let group = self.group_state.selected().unwrap_or(0);
+        let file = self.file_state.selected().unwrap_or(0);
+        self.groups[group].members[file].mark = mark;
+        self.unsaved = true;
+        self.status.clear();
+        self.focus = Focus::Files;
+        self.step(1);
+    }
+
+    /// Writes the marks to `output_file`, unless a group has files to delete
+    /// or link but none to keep.
+    fn save(&mut self, output_file: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut groups = Vec::new();
+        for (idx, group) in self.groups.iter().enumerate() {
+            let marked = |mark: Mark| -> Vec<&str> {
+                group
+                    .members
+                    .iter()
+                    .filter(|m| m.mark == Some(mark))
+                    .map(|m| m.path.as_str())
+                    .collect()
+            };
+            let plan = PlanGroup {
+                keep: marked(Mark::Keep),
+                delete: marked(Mark::Delete),
+                link: marked(Mark::Link),
+            };
+            if plan.delete.is_empty() && plan.link.is_empty() {
+                continue;
+            }
+            if plan.keep.is_empty() {
+                self.group_state.select(Some(idx));
+                self.file_state.select(Some(0));
+                self.status = format!("Keep a file of {} first", group.file_name);
+                return Ok(());
+            }
+            groups.push(plan);
+        }
+        let count = groups.len();
+        let plan = Plan {
+            report: self.report.clone(),
+            groups,
+        };
+        let mut out = BufWriter::new(fs::File::create(output_file)?);
+        serde_json::to_writer_pretty(&mut out, &plan)?;
+        out.flush()?;
+        self.unsaved = false;
+        self.status = format!("Saved {} groups to {}", count, output_file);
+        Ok(())
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main, footer] =
+            Layout::vertical([Constraint::Min(0), Constraint::Length(1)]).areas(frame.area());
+        let [left, right] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+                .areas(main);
+        let [files, preview_area] =
+            Layout::vertical([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(right);
+        let border = |focused: bool| {
+            if focused {
+                Style::new().bold()
+            } else {
+                Style::new().dim()
+            }
+        };
+
+        let groups: Vec<ListItem> = self
+            .groups
+            .iter()
+            .map(|g| {
+                let marked = g.members.iter().any(|m| m.mark.is_some());
+                ListItem::new(format!(
+                    "{} {:>10}  {}x  {}",
+                    if marked { "*" } else { " " },
+                    g.wasted.map(human_bytes).unwrap_or_else(|| "n/a".into()),
+                    g.members.len(),
+                    g.file_name
+                ))
+            })
+            .collect();
+        let groups = List::new(groups)
+            .block(
+                Block::bordered()
+                    .title(format!(
+                        "Groups by {} ({})",
+                        if self.identical {
+                            "wasted space"
+                        } else {
+                            "size"
+                        },
+                        self.groups.len()
+                    ))
+                    .border_style(border(self.focus == Focus::Groups)),
+            )
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(groups, left, &mut self.group_state);
+
+        let members: Vec<ListItem> = self
+            .group()
+            .members
+            .iter()
+            .map(|m| {
+                let mark = match m.mark {
+                    Some(Mark::Keep) => "[keep]  ",
+                    Some(Mark::Delete) => "[delete]",
+                    Some(Mark::Link) => "[link]  ",
+                    None => "        ",
+                };
+                let size = m.size.map(human_bytes).unwrap_or_else(|| "?".into());
+                ListItem::new(format!("{} {:>10}  {}", mark, size, m.path))
+            })
+            .collect();
+        let members = List::new(members)
+            .block(
+                Block::bordered()
+                    .title("Files")
+                    .border_style(border(self.focus == Focus::Files)),
+            )
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(members, files, &mut self.file_state);
+
+        let path = self.member().path.clone();
+        if self
+            .preview
+            .as_ref()
+            .is_none_or(|(shown, _)| *shown != path)
+        {
+            self.preview = Some((path.clone(), preview(&path)));
+        }
+        let text = self.preview.as_ref().map(|(_, text)| text.as_str());
+        let lines: Vec<Line> = text
+            .unwrap_or_default()
+            .lines()
+            .take(preview_area.height as usize)
+            .map(Line::raw)
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title(path)),
+            preview_area,
+        );
+
+        let help = "↑↓ move  tab switch  k keep  d delete  l link  u unmark  s save  q quit";
+        let status = if self.status.is_empty() {
+            help
+        } else {
+            &self.status
+        };
+        frame.render_widget(Paragraph::new(status), footer);
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<(), Box<dyn std::error::Error>> {
+        let mut quitting = false;
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let quit = matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                || (key.code == KeyCode::Char('c')
+                    && key.modifiers.contains(KeyModifiers::CONTROL));
+            if quit {
+                if !self.unsaved || quitting {
+                    return Ok(());
+                }
+                quitting = true;
+                self.status = "Unsaved marks, press q again to quit or s to save".into();
+                continue;
+            }
+            quitting = false;
+            if key.code != KeyCode::Char('d') {
+                self.confirming = false;
+            }
+            match key.code {
+                KeyCode::Up => self.step(-1),
+                KeyCode::Down => self.step(1),
+                KeyCode::PageUp => self.step(-10),
+                KeyCode::PageDown => self.step(10),
+                KeyCode::Tab | KeyCode::Left | KeyCode::Right => {
+                    self.focus = match self.focus {
+                        Focus::Groups => Focus::Files,
+                        Focus::Files => Focus::Groups,
+                    }
+                }
+                KeyCode::Char('k') => self.mark(Some(Mark::Keep)),
+                KeyCode::Char('d') => self.mark(Some(Mark::Delete)),
+                KeyCode::Char('l') => self.mark(Some(Mark::Link)),
+                KeyCode::Char('u') => self.mark(None),
+                KeyCode::Char('s') => {
+                    if let Err(e) = self.save("./plan.json") {
+                        self.status = format!("Couldn't save plan: {}", e);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Browses the duplicate groups of the report at `path` in the terminal,
+/// largest waste first, or largest files first when they weren't matched on
+/// content, and saves the files marked to keep, delete or link to
+/// ./plan.json.
+pub fn review(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    if !io::stdout().is_terminal() {
+        return Err("review needs a terminal".into());
+    }
+    let (groups, identical) = load(path)?;
+    let mut review = Review {
+        report: path.display().to_string(),
+        groups,
+        identical,
+        confirming: false,
+        group_state: ListState::default().with_selected(Some(0)),
+        file_state: ListState::default().with_selected(Some(0)),
+        focus: Focus::Groups,
+        preview: None,
+        status: String::new(),
+        unsaved: false,
+    };
+    let mut terminal = ratatui::try_init()?;
+    let result = review.run(&mut terminal);
+    ratatui::restore();
+    result
+}
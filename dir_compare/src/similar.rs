@@ -0,0 +1,119 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{args::Scope, Dir, Duplicate};
+
+#[derive(Debug, Serialize)]
+pub struct SimilarPair<'a> {
+    pub first: &'a str,
+    pub second: &'a str,
+    pub similarity: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distance: Option<u32>,
+}
+
+/// Files linked to each other through a chain of similar pairs.
+#[derive(Debug, Serialize)]
+pub struct SimilarGroup<'a> {
+    pub files: Vec<&'a str>,
+    pub pairs: Vec<SimilarPair<'a>>,
+    pub scope: Scope,
+    /// The root of every file.
+    pub roots: Vec<usize>,
+}
+
+fn find_root(parents: &mut [usize], mut idx: usize) -> usize {
+    while parents[idx] != idx {
+        parents[idx] = parents[parents[idx]];
+        idx = parents[idx];
+    }
+    idx
+}
+
+/// Joins pairs that share a file into groups, most similar pairs first. For
+/// intra-root groups only pairs within a root are joined, and for cross-root
+/// ones only groups spanning roots are kept.
+pub fn group_pairs<'a>(
+    mut pairs: Vec<SimilarPair<'a>>,
+    dirs: &[Dir],
+    scope: Option<Scope>,
+) -> Vec<SimilarGroup<'a>> {
+    let roots: HashMap<&str, usize> = dirs
+        .iter()
+        .enumerate()
+        .flat_map(|(root, d)| d.files.iter().map(move |f| (f.path.as_str(), root)))
+        .collect();
+    if scope == Some(Scope::IntraRoot) {
+        pairs.retain(|pair| roots[pair.first] == roots[pair.second]);
+    }
+
+    let mut ids: BTreeMap<&str, usize> = BTreeMap::new();
+    for pair in pairs.iter() {
+        let next = ids.len();
+        ids.entry(pair.first).or_insert(next);
+        let next = ids.len();
+        ids.entry(pair.second).or_insert(next);
+    }
+
+    let mut parents: Vec<usize> = (0..ids.len()).collect();
+    for pair in pairs.iter() {
+        let a = find_root(&mut parents, ids[pair.first]);
+        let b = find_root(&mut parents, ids[pair.second]);
+        parents[a] = b;
+    }
+
+    let mut groups: BTreeMap<usize, SimilarGroup> = BTreeMap::new();
+    for (file, id) in ids.iter() {
+        let root = find_root(&mut parents, *id);
+        groups
+            .entry(root)
+            .or_insert_with(|| SimilarGroup {
+                files: Vec::new(),
+                pairs: Vec::new(),
+                scope: Scope::IntraRoot,
+                roots: Vec::new(),
+            })
+            .files
+            .push(file);
+    }
+    for pair in pairs {
+        let root = find_root(&mut parents, ids[pair.first]);
+        if let Some(group) = groups.get_mut(&root) {
+            group.pairs.push(pair);
+        }
+    }
+
+    let mut groups: Vec<SimilarGroup> = groups.into_values().collect();
+    for group in groups.iter_mut() {
+        group.files.sort_by_key(|f| (roots[f], *f));
+        group.roots = group.files.iter().map(|f| roots[f]).collect();
+        group.scope = Scope::of(group.roots.iter().copied());
+        group
+            .pairs
+            .sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+    }
+    if scope == Some(Scope::CrossRoot) {
+        groups.retain(|group| group.scope == Scope::CrossRoot);
+    }
+    groups
+}
+
+/// Lists every group as a duplicate of its first file, so that the groups can
+/// be browsed like exact matches.
+pub fn as_duplicates<'a>(groups: &[SimilarGroup<'a>]) -> Vec<Duplicate<'a>> {
+    groups
+        .iter()
+        .map(|group| Duplicate {
+            file_name: group.files[0]
+                .rsplit(std::path::MAIN_SEPARATOR)
+                .next()
+                .unwrap_or(group.files[0]),
+            first_dir_match: group.files[0],
+            second_dir_match: group.files[1..].iter().map(|f| f.to_string()).collect(),
+            scope: group.scope,
+            roots: group.roots.clone(),
+            decompressed: Vec::new(),
+            metadata_mismatches: Vec::new(),
+        })
+        .collect()
+}
@@ -0,0 +1,767 @@
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+use std::{
+    cell::RefCell,
+    cmp::Reverse,
+    collections::BinaryHeap,
+    env, fs,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    process,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    archive::is_member,
+    args::Scope,
+    cancel,
+    decompress::codec_of,
+    hash::{hash_content, set_hash},
+    progress,
+    stats::{Member, RootStats, Stats},
+    File,
+};
+
+/// Rough memory one file of the index takes, strings included.
+const ENTRY_BYTES: u64 = 256;
+const MIN_RUN_LEN: usize = 1024;
+/// Most runs read at once. More are merged in passes first, so that open
+/// files and read buffers stay bounded however many runs there are.
+const FAN_IN: usize = 64;
+
+static SPILL: Mutex<Option<Spill>> = Mutex::new(None);
+/// The spill dir while it exists, for exits that skip destructors.
+static SPILL_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+struct Spill {
+    dir: PathBuf,
+    run_len: usize,
+    decompress: bool,
+    root: usize,
+    roots: Vec<RootStats>,
+    runs: Vec<PathBuf>,
+}
+
+impl Spill {
+    fn next_run(&self, stage: &str, idx: usize) -> PathBuf {
+        self.dir.join(format!("{}-{}.jsonl", stage, idx))
+    }
+}
+
+/// The dir spill files are written to, only readable by the user. It is
+/// removed with everything in it when dropped, on errors and panics too.
+pub struct SpillDir {
+    path: PathBuf,
+}
+
+impl SpillDir {
+    fn create() -> io::Result<Self> {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.subsec_nanos())
+            .unwrap_or_default();
+        for attempt in 0..100 {
+            let path = env::temp_dir().join(format!(
+                "dir_compare-{}-{:x}",
+                process::id(),
+                nanos.wrapping_add(attempt)
+            ));
+            match fs::DirBuilder::new().mode(0o700).create(&path) {
+                Ok(()) => {
+                    *SPILL_DIR.lock().unwrap() = Some(path.clone());
+                    return Ok(Self { path });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "no free name for the spill dir",
+        ))
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        SPILL_DIR.lock().unwrap().take();
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            eprintln!("Spill err: {}, Dir {:?}", e, self.path);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    size: u64,
+    /// Size of the decompressed content, for compressed files hashed that way.
+    content_size: u64,
+    root: usize,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    decompressed: bool,
+}
+
+/// The first line of a duplicate group in the groups file. The paths of its
+/// copies follow, one per line, then the roots of all its files, then the
+/// paths of its decompressed files.
+#[derive(Debug, Serialize, Deserialize)]
+struct GroupHeader {
+    file_name: String,
+    first_dir_match: String,
+    scope: Scope,
+    copies: usize,
+    decompressed: usize,
+}
+
+type Lines = RefCell<io::Lines<BufReader<fs::File>>>;
+
+/// The next `count` values of the groups file, paths or roots.
+struct ValueLines<'a> {
+    count: usize,
+    lines: &'a Lines,
+    path: &'a Path,
+}
+
+impl Serialize for ValueLines<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let values = (0..self.count).filter_map(|_| {
+            let line = self.lines.borrow_mut().next()?;
+            line.map_err(|e| e.to_string())
+                .and_then(|line| {
+                    serde_json::from_str::<serde_json::Value>(&line).map_err(|e| e.to_string())
+                })
+                .map_err(|e| eprintln!("Spill err: {}, File {:?}", e, self.path))
+                .ok()
+        });
+        serializer.collect_seq(values)
+    }
+}
+
+/// A duplicate group read back from disk, shaped like `Duplicate`, its paths
+/// streamed from the groups file as it is serialized.
+struct GroupLines<'a> {
+    header: GroupHeader,
+    lines: &'a Lines,
+    path: &'a Path,
+}
+
+impl Serialize for GroupLines<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let values = |count| ValueLines {
+            count,
+            lines: self.lines,
+            path: self.path,
+        };
+        let mut group = serializer.serialize_struct("Group", 6)?;
+        group.serialize_field("file_name", &self.header.file_name)?;
+        group.serialize_field("first_dir_match", &self.header.first_dir_match)?;
+        group.serialize_field("second_dir_match", &values(self.header.copies))?;
+        group.serialize_field("scope", &self.header.scope)?;
+        group.serialize_field("roots", &values(self.header.copies + 1))?;
+        if self.header.decompressed > 0 {
+            group.serialize_field("decompressed", &values(self.header.decompressed))?;
+        }
+        group.end()
+    }
+}
+
+/// Duplicate groups kept on disk until the report is written.
+#[derive(Debug)]
+pub struct Groups {
+    path: PathBuf,
+    pub count: usize,
+    pub copies: usize,
+    pub cross: usize,
+}
+
+impl Serialize for Groups {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let file = fs::File::open(&self.path).map_err(serde::ser::Error::custom)?;
+        let lines: Lines = RefCell::new(BufReader::new(file).lines());
+        // Each header is read once the group before it has been written, so
+        // the lines are taken in order.
+        let groups = std::iter::from_fn(|| loop {
+            let line = lines.borrow_mut().next()?;
+            match line
+                .map_err(|e| e.to_string())
+                .and_then(|line| serde_json::from_str(&line).map_err(|e| e.to_string()))
+            {
+                Ok(header) => {
+                    return Some(GroupLines {
+                        header,
+                        lines: &lines,
+                        path: &self.path,
+                    })
+                }
+                Err(e) => eprintln!("Spill err: {}, File {:?}", e, self.path),
+            }
+        });
+        serializer.collect_seq(groups)
+    }
+}
+
+/// Starts keeping the file index on disk, holding about `max_memory` bytes
+/// of it in memory at a time. The spill files live as long as the returned
+/// dir. With `decompress`, compressed files are hashed as they are spilled,
+/// since their content size is unknown until then.
+pub fn init(max_memory: u64, decompress: bool) -> io::Result<SpillDir> {
+    let dir = SpillDir::create()?;
+    *SPILL.lock().unwrap() = Some(Spill {
+        dir: dir.path.clone(),
+        run_len: ((max_memory / ENTRY_BYTES) as usize).max(MIN_RUN_LEN),
+        decompress,
+        root: 0,
+        roots: Vec::new(),
+        runs: Vec::new(),
+    });
+    Ok(dir)
+}
+
+/// Removes the spill dir, if there is one, before exiting on a signal.
+pub fn remove_dir() {
+    if let Some(path) = SPILL_DIR.lock().unwrap().take() {
+        let _ = fs::remove_dir_all(path);
+    }
+}
+
+/// Tells which root the files walked from now on belong to.
+pub fn set_root(root: usize, path: &Path) {
+    if let Some(spill) = SPILL.lock().unwrap().as_mut() {
+        spill.root = root;
+        spill.roots.push(RootStats {
+            path: path.display().to_string(),
+            files: 0,
+            bytes: 0,
+        });
+    }
+}
+
+fn write_run(path: &Path, entries: &[Entry]) -> io::Result<()> {
+    let mut out = BufWriter::new(fs::File::create(path)?);
+    for entry in entries {
+        serde_json::to_writer(&mut out, entry)?;
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+/// Moves the walked `files` to a run on disk once there are enough of them.
+pub fn spill(files: &mut Vec<File>) {
+    let run_len = match SPILL.lock().unwrap().as_ref() {
+        Some(spill) => spill.run_len,
+        None => return,
+    };
+    if files.len() >= run_len {
+        flush(files);
+    }
+}
+
+/// Moves all walked `files` to a run on disk, sorted by content size.
+pub fn flush(files: &mut Vec<File>) {
+    let mut state = SPILL.lock().unwrap();
+    let Some(spill) = state.as_mut() else {
+        return;
+    };
+    if files.is_empty() {
+        return;
+    }
+    let root = &mut spill.roots[spill.root];
+    for file in files.iter().filter(|f| !is_member(&f.path)) {
+        root.files += 1;
+        root.bytes += file.size;
+    }
+    let mut entries: Vec<Entry> = files
+        .drain(..)
+        .map(|mut file| {
+            let compressed = spill.decompress && codec_of(Path::new(&file.path)).is_some();
+            let content_size = if compressed && file.hash.is_none() {
+                set_hash(&mut file, true).unwrap_or(file.size)
+            } else {
+                file.size
+            };
+            Entry {
+                size: file.size,
+                content_size,
+                root: spill.root,
+                path: file.path,
+                hash: file.hash,
+                decompressed: file.decompressed,
+            }
+        })
+        .collect();
+    entries.sort_by(|a, b| (a.content_size, &a.path).cmp(&(b.content_size, &b.path)));
+    let run = spill.next_run("walk", spill.runs.len());
+    match write_run(&run, &entries) {
+        Ok(()) => spill.runs.push(run),
+        Err(e) => {
+            eprintln!("Spill err: {}, File {:?}", e, run);
+            progress::error();
+        }
+    }
+}
+
+/// Parses a line of a run, counting lines that can't be read as errors.
+fn parse_entry(line: io::Result<String>) -> Option<Entry> {
+    match line
+        .map_err(|e| e.to_string())
+        .and_then(|line| serde_json::from_str::<Entry>(&line).map_err(|e| e.to_string()))
+    {
+        Ok(entry) => Some(entry),
+        Err(e) => {
+            eprintln!("Spill err: {}", e);
+            progress::error();
+            None
+        }
+    }
+}
+
+/// Reads runs sorted by `key` back as one sorted stream.
+struct Merge<K: Ord> {
+    readers: Vec<io::Lines<BufReader<fs::File>>>,
+    heap: BinaryHeap<Reverse<(K, usize)>>,
+    heads: Vec<Option<Entry>>,
+    key: fn(&Entry) -> K,
+}
+
+impl<K: Ord> Merge<K> {
+    fn new(runs: &[PathBuf], key: fn(&Entry) -> K) -> io::Result<Self> {
+        let mut merge = Self {
+            readers: Vec::new(),
+            heap: BinaryHeap::new(),
+            heads: Vec::new(),
+            key,
+        };
+        for (idx, run) in runs.iter().enumerate() {
+            merge
+                .readers
+                .push(BufReader::new(fs::File::open(run)?).lines());
+            merge.heads.push(None);
+            merge.advance(idx);
+        }
+        Ok(merge)
+    }
+
+    fn advance(&mut self, idx: usize) {
+        if let Some(entry) = self.readers[idx].by_ref().find_map(parse_entry) {
+            self.heap.push(Reverse(((self.key)(&entry), idx)));
+            self.heads[idx] = Some(entry);
+        }
+    }
+}
+
+impl<K: Ord> Iterator for Merge<K> {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        let Reverse((_, idx)) = self.heap.pop()?;
+        let entry = self.heads[idx].take();
+        self.advance(idx);
+        entry
+    }
+}
+
+/// Merges `runs` sorted by `key`, `fan_in` at a time into new ones named by
+/// `next_run`, until at most `fan_in` are left. Merged runs are removed.
+fn narrow<K: Ord>(
+    mut runs: Vec<PathBuf>,
+    fan_in: usize,
+    key: fn(&Entry) -> K,
+    mut next_run: impl FnMut() -> PathBuf,
+) -> io::Result<Vec<PathBuf>> {
+    while runs.len() > fan_in {
+        let mut merged = Vec::new();
+        for batch in runs.chunks(fan_in) {
+            if let [run] = batch {
+                merged.push(run.clone());
+                continue;
+            }
+            let run = next_run();
+            let mut out = BufWriter::new(fs::File::create(&run)?);
+            for entry in Merge::new(batch, key)? {
+                write_line(&mut out, &entry)?;
+            }
+            out.flush()?;
+            for path in batch {
+                fs::remove_file(path)?;
+            }
+            merged.push(run);
+        }
+        runs = merged;
+    }
+    Ok(runs)
+}
+
+/// The entries of one group as they stream in, held in memory up to the run
+/// length and in an overflow file beyond it, so that a group of any size
+/// can be read several times without holding all of it.
+struct GroupBuf {
+    entries: Vec<Entry>,
+    overflow: Option<BufWriter<fs::File>>,
+    path: PathBuf,
+    limit: usize,
+    len: usize,
+    cross: bool,
+    decompressed: usize,
+}
+
+impl GroupBuf {
+    fn new(path: PathBuf, limit: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            overflow: None,
+            path,
+            limit,
+            len: 0,
+            cross: false,
+            decompressed: 0,
+        }
+    }
+
+    fn push(&mut self, entry: Entry) -> io::Result<()> {
+        self.len += 1;
+        self.cross |= self.entries.first().is_some_and(|e| e.root != entry.root);
+        if entry.decompressed {
+            self.decompressed += 1;
+        }
+        if self.entries.len() < self.limit {
+            self.entries.push(entry);
+            return Ok(());
+        }
+        let out = match self.overflow.as_mut() {
+            Some(out) => out,
+            None => self
+                .overflow
+                .insert(BufWriter::new(fs::File::create(&self.path)?)),
+        };
+        serde_json::to_writer(&mut *out, &entry)?;
+        out.write_all(b"\n")
+    }
+
+    fn iter(&mut self) -> io::Result<impl Iterator<Item = Entry> + '_> {
+        let overflow = match self.overflow.as_mut() {
+            Some(out) => {
+                out.flush()?;
+                let lines = BufReader::new(fs::File::open(&self.path)?).lines();
+                Some(lines.filter_map(parse_entry))
+            }
+            None => None,
+        };
+        Ok(self
+            .entries
+            .iter()
+            .cloned()
+            .chain(overflow.into_iter().flatten()))
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.overflow = None;
+        self.len = 0;
+        self.cross = false;
+        self.decompressed = 0;
+    }
+}
+
+fn member(entry: Entry) -> Member {
+    Member {
+        root: entry.root,
+        path: entry.path,
+        size: entry.size,
+    }
+}
+
+fn write_line(out: &mut impl Write, value: &impl Serialize) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, value)?;
+    out.write_all(b"\n")
+}
+
+/// Hands every spilled file to `on_file`, one run at a time.
+pub fn each_file(mut on_file: impl FnMut(Member) -> io::Result<()>) -> io::Result<()> {
+    let state = SPILL.lock().unwrap();
+    let Some(spill) = state.as_ref() else {
+        return Ok(());
+    };
+    for run in spill.runs.iter() {
+        let lines = BufReader::new(fs::File::open(run)?).lines();
+        for entry in lines.filter_map(parse_entry) {
+            on_file(member(entry))?;
+        }
+    }
+    Ok(())
+}
+
+/// Groups the spilled files by content in two passes over the disk: a merge
+/// by size picks the files worth hashing and writes their hashes to new runs,
+/// and a merge of those by hash yields the groups, each also handed to
+/// `on_group` with its file name, scope and members. Groups are streamed,
+/// so that none is held in memory whole.
+pub fn find_duplicates(
+    decompress: bool,
+    scope: Option<Scope>,
+    top: usize,
+    mut on_group: impl FnMut(&str, Scope, &mut dyn Iterator<Item = Member>) -> io::Result<()>,
+) -> io::Result<(Groups, Stats)> {
+    let mut spill = SPILL
+        .lock()
+        .unwrap()
+        .take()
+        .expect("spilling was not started");
+    let mut groups = Groups {
+        path: spill.next_run("groups", 0),
+        count: 0,
+        copies: 0,
+        cross: 0,
+    };
+    progress::set_phase("hash", None);
+    let walk_runs = std::mem::take(&mut spill.runs);
+
+    let mut hashed: Vec<Entry> = Vec::new();
+    let mut hash_runs: Vec<PathBuf> = Vec::new();
+    let mut hash = |mut entry: Entry| -> io::Result<()> {
+        if entry.hash.is_none() {
+            match hash_content(Path::new(&entry.path), decompress) {
+                Ok(content) => {
+                    entry.hash = Some(content.hash);
+                    entry.decompressed = content.decompressed;
+                }
+                Err(e) => {
+                    if !cancel::stopped() {
+                        eprintln!("Hash err: {}, File {:?}", e, entry.path);
+                        progress::error();
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        hashed.push(entry);
+        if hashed.len() >= spill.run_len {
+            hashed.sort_by(|a, b| (&a.hash, a.root, &a.path).cmp(&(&b.hash, b.root, &b.path)));
+            let run = spill.next_run("hash", hash_runs.len());
+            write_run(&run, &hashed)?;
+            hash_runs.push(run);
+            hashed.clear();
+        }
+        Ok(())
+    };
+
+    // A file is only hashed when another one has its content size. The
+    // first of a size waits for the next one to tell.
+    let mut first_of_size: Option<Entry> = None;
+    let mut size = None;
+    let mut merges = 0;
+    let runs = narrow(
+        walk_runs,
+        FAN_IN,
+        |e| e.content_size,
+        || {
+            merges += 1;
+            spill.next_run("walk-merge", merges)
+        },
+    )?;
+    for entry in Merge::new(&runs, |e| e.content_size)? {
+        if cancel::stopped() {
+            break;
+        }
+        if size != Some(entry.content_size) {
+            size = Some(entry.content_size);
+            first_of_size = Some(entry);
+            continue;
+        }
+        if let Some(first) = first_of_size.take() {
+            hash(first)?;
+        }
+        hash(entry)?;
+    }
+    if !hashed.is_empty() {
+        hashed.sort_by(|a, b| (&a.hash, a.root, &a.path).cmp(&(&b.hash, b.root, &b.path)));
+        let run = spill.next_run("hash", hash_runs.len());
+        write_run(&run, &hashed)?;
+        hash_runs.push(run);
+    }
+    drop(hashed);
+
+    // Groups here always have identical content.
+    let mut stats = Stats::new(std::mem::take(&mut spill.roots), top, true);
+    let mut out = BufWriter::new(fs::File::create(&groups.path)?);
+    let mut write_group = |buf: &mut GroupBuf| -> io::Result<()> {
+        if buf.len < 2 {
+            return Ok(());
+        }
+        let group_scope = if buf.cross {
+            Scope::CrossRoot
+        } else {
+            Scope::IntraRoot
+        };
+        if scope.is_some_and(|scope| scope != group_scope) {
+            return Ok(());
+        }
+        let first = &buf.entries[0];
+        let header = GroupHeader {
+            file_name: Path::new(&first.path)
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or(&first.path)
+                .to_string(),
+            first_dir_match: first.path.clone(),
+            scope: group_scope,
+            copies: buf.len - 1,
+            decompressed: buf.decompressed,
+        };
+        stats.add_group(buf.iter()?.map(member));
+        on_group(&header.file_name, group_scope, &mut buf.iter()?.map(member))?;
+        write_line(&mut out, &header)?;
+        for entry in buf.iter()?.skip(1) {
+            write_line(&mut out, &entry.path)?;
+        }
+        for entry in buf.iter()? {
+            write_line(&mut out, &entry.root)?;
+        }
+        for entry in buf.iter()?.filter(|e| e.decompressed) {
+            write_line(&mut out, &entry.path)?;
+        }
+        groups.count += 1;
+        groups.copies += header.copies;
+        if group_scope == Scope::CrossRoot {
+            groups.cross += 1;
+        }
+        Ok(())
+    };
+
+    // Entries come sorted by root within a hash, so intra-root groups are
+    // cut where the root changes.
+    let split = scope == Some(Scope::IntraRoot);
+    let mut buf = GroupBuf::new(spill.next_run("group", 0), spill.run_len);
+    let by_hash = |e: &Entry| (e.hash.clone(), e.root, e.path.clone());
+    let mut merges = 0;
+    let hash_runs = narrow(hash_runs, FAN_IN, by_hash, || {
+        merges += 1;
+        spill.next_run("hash-merge", merges)
+    })?;
+    for entry in Merge::new(&hash_runs, by_hash)? {
+        if buf
+            .entries
+            .first()
+            .is_some_and(|first| first.hash != entry.hash || (split && first.root != entry.root))
+        {
+            write_group(&mut buf)?;
+            buf.clear();
+        }
+        buf.push(entry)?;
+    }
+    write_group(&mut buf)?;
+    out.flush()?;
+    stats.finish();
+    Ok((groups, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn entry(size: u64, path: &str) -> Entry {
+        Entry {
+            size,
+            content_size: size,
+            root: 0,
+            path: path.to_string(),
+            hash: None,
+            decompressed: false,
+        }
+    }
+
+    fn write_runs(tmp: &TempDir, runs: &[&[(u64, &str)]]) -> Vec<PathBuf> {
+        runs.iter()
+            .enumerate()
+            .map(|(idx, entries)| {
+                let path = tmp.path.join(format!("run-{}", idx));
+                let entries: Vec<Entry> = entries.iter().map(|(s, p)| entry(*s, p)).collect();
+                write_run(&path, &entries).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    fn merged(runs: &[PathBuf]) -> Vec<(u64, String)> {
+        Merge::new(runs, |e| (e.content_size, e.path.clone()))
+            .unwrap()
+            .map(|e| (e.content_size, e.path))
+            .collect()
+    }
+
+    #[test]
+    fn merges_sorted_runs_into_one_stream() {
+        let tmp = TempDir::new("spill-merge");
+        let runs = write_runs(
+            &tmp,
+            &[
+                &[(1, "a"), (5, "e"), (9, "i")],
+                &[],
+                &[(2, "b"), (5, "d"), (10, "j")],
+                &[(3, "c"), (7, "g")],
+            ],
+        );
+        let sizes: Vec<u64> = merged(&runs).into_iter().map(|(size, _)| size).collect();
+        assert_eq!(sizes, vec![1, 2, 3, 5, 5, 7, 9, 10]);
+        assert_eq!(merged(&runs)[3..5], [(5, "d".into()), (5, "e".into())]);
+        assert!(merged(&[]).is_empty());
+    }
+
+    #[test]
+    fn merges_more_runs_than_the_fan_in_in_passes() {
+        let tmp = TempDir::new("spill-narrow");
+        let entries: Vec<Vec<(u64, String)>> = (0..10)
+            .map(|run| {
+                (0..5)
+                    .map(|i| (i * 10 + run, format!("{}-{}", run, i)))
+                    .collect()
+            })
+            .collect();
+        let runs: Vec<Vec<(u64, &str)>> = entries
+            .iter()
+            .map(|run| run.iter().map(|(s, p)| (*s, p.as_str())).collect())
+            .collect();
+        let runs: Vec<&[(u64, &str)]> = runs.iter().map(Vec::as_slice).collect();
+        let written = write_runs(&tmp, &runs);
+
+        let mut merges = 0;
+        let narrowed = narrow(
+            written.clone(),
+            3,
+            |e| (e.content_size, e.path.clone()),
+            || {
+                merges += 1;
+                tmp.path.join(format!("merge-{}", merges))
+            },
+        )
+        .unwrap();
+        // Ten runs take two passes: 3 + 3 + 3 + 1, then 3 + 1.
+        assert_eq!(narrowed.len(), 2);
+        assert_eq!(merges, 4);
+        // The last run is left over in both passes and kept as it is.
+        assert!(written[..9].iter().all(|run| !run.exists()));
+        assert_eq!(narrowed[1], written[9]);
+        let sizes: Vec<u64> = merged(&narrowed)
+            .into_iter()
+            .map(|(size, _)| size)
+            .collect();
+        assert_eq!(sizes, (0..50).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn skips_lines_that_dont_parse() {
+        let tmp = TempDir::new("spill-bad-line");
+        let runs = write_runs(&tmp, &[&[(1, "a"), (4, "d")], &[(2, "b")]]);
+        let mut text = fs::read_to_string(&runs[1]).unwrap();
+        text.push_str("{\"size\":\n");
+        text.push_str(&serde_json::to_string(&entry(3, "c")).unwrap());
+        text.push('\n');
+        fs::write(&runs[1], text).unwrap();
+        let paths: Vec<String> = merged(&runs).into_iter().map(|(_, path)| path).collect();
+        assert_eq!(paths, vec!["a", "b", "c", "d"]);
+    }
+}
@@ -0,0 +1,388 @@
+use serde::Serialize;
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    fs,
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use crate::{archive::is_member, progress::human_bytes, Dir, Duplicate};
+
+/// Files and bytes walked under one root, archive members left out.
+#[derive(Debug, Serialize)]
+pub struct RootStats {
+    pub path: String,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// One file of a duplicate group, the first one being the copy to keep.
+pub struct Member {
+    pub root: usize,
+    pub path: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupStats {
+    pub first: String,
+    pub copies: usize,
+    pub size: u64,
+    pub reclaimable_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Breakdown {
+    pub key: String,
+    pub groups: usize,
+    pub copies: usize,
+    pub reclaimable_bytes: Option<u64>,
+}
+
+/// Totals over the duplicate groups. Reclaimable bytes count what deleting
+/// every copy but the first would free on disk: hard links to one inode are
+/// counted once, and archive members not at all. They are only known when
+/// groups were matched on identical content, and `None` otherwise.
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub roots: Vec<RootStats>,
+    pub groups: usize,
+    pub redundant_copies: usize,
+    pub reclaimable_bytes: Option<u64>,
+    pub top_groups: Vec<GroupStats>,
+    pub by_extension: Vec<Breakdown>,
+    pub by_top_dir: Vec<Breakdown>,
+    #[serde(skip)]
+    top: usize,
+    #[serde(skip)]
+    identical: bool,
+    #[serde(skip)]
+    extensions: HashMap<String, Breakdown>,
+    #[serde(skip)]
+    top_dirs: HashMap<String, Breakdown>,
+}
+
+fn extension(path: &str) -> String {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| format!(".{}", ext.to_lowercase()))
+        .unwrap_or_else(|| "(none)".into())
+}
+
+/// The first dir below `root` that `path` lies in, or the root itself.
+fn top_dir(root: &str, path: &str) -> String {
+    let relative = Path::new(path)
+        .strip_prefix(root)
+        .unwrap_or(Path::new(path));
+    let mut components = relative.components();
+    match (components.next(), components.next()) {
+        (Some(top), Some(_)) => Path::new(root).join(top).display().to_string(),
+        _ => root.to_string(),
+    }
+}
+
+/// Totals of what was walked under each of `dirs`.
+pub fn root_stats(dirs: &[Dir]) -> Vec<RootStats> {
+    dirs.iter()
+        .map(|dir| {
+            let real_files = dir.files.iter().filter(|f| !is_member(&f.path));
+            RootStats {
+                path: dir.name.clone(),
+                files: real_files.clone().count() as u64,
+                bytes: real_files.map(|f| f.size).sum(),
+            }
+        })
+        .collect()
+}
+
+/// Adds up the stats of `duplicates`, looking their files up in `dirs`.
+/// `identical` tells whether the groups were matched on content.
+pub fn duplicate_stats(
+    dirs: &[Dir],
+    duplicates: &[Duplicate],
+    top: usize,
+    identical: bool,
+) -> Stats {
+    let files: HashMap<&str, (usize, u64)> = dirs
+        .iter()
+        .enumerate()
+        .flat_map(|(root, d)| {
+            d.files
+                .iter()
+                .map(move |f| (f.path.as_str(), (root, f.size)))
+        })
+        .collect();
+    let mut stats = Stats::new(root_stats(dirs), top, identical);
+    for dup in duplicates {
+        let members = std::iter::once(dup.first_dir_match)
+            .chain(dup.second_dir_match.iter().map(String::as_str))
+            .filter_map(|path| {
+                let (root, size) = files.get(path)?;
+                Some(Member {
+                    root: *root,
+                    path: path.to_string(),
+                    size: *size,
+                })
+            });
+        stats.add_group(members);
+    }
+    stats.finish();
+    stats
+}
+
+fn bytes_or_na(bytes: Option<u64>) -> String {
+    bytes.map(human_bytes).unwrap_or_else(|| "n/a".into())
+}
+
+fn inode(path: &str) -> Option<(u64, u64)> {
+    if is_member(path) {
+        return None;
+    }
+    fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+impl Stats {
+    pub fn new(roots: Vec<RootStats>, top: usize, identical: bool) -> Self {
+        Self {
+            roots,
+            groups: 0,
+            redundant_copies: 0,
+            reclaimable_bytes: identical.then_some(0),
+            top_groups: Vec::new(),
+            by_extension: Vec::new(),
+            by_top_dir: Vec::new(),
+            top,
+            identical,
+            extensions: HashMap::new(),
+            top_dirs: HashMap::new(),
+        }
+    }
+
+    /// Adds a group, its first member being the copy to keep. Members are
+    /// taken one at a time, so that a group needn't be held whole.
+    pub fn add_group(&mut self, members: impl IntoIterator<Item = Member>) {
+        let mut members = members.into_iter();
+        let Some(first) = members.next() else {
+            return;
+        };
+        // Empty files free nothing, so their inodes aren't worth keeping.
+        let mut seen: HashSet<(u64, u64)> = HashSet::new();
+        if first.size > 0 {
+            seen.extend(inode(&first.path));
+        }
+        let mut copies = 0;
+        let identical = self.identical;
+        let mut group_bytes = identical.then_some(0);
+        let mut group_keys: HashSet<(bool, String)> = HashSet::new();
+
+        for copy in members {
+            copies += 1;
+            let freed = match inode(&copy.path) {
+                _ if copy.size == 0 => 0,
+                Some(inode) if seen.insert(inode) => copy.size,
+                Some(_) => 0,
+                None if is_member(&copy.path) => 0,
+                None => copy.size,
+            };
+            if let Some(bytes) = group_bytes.as_mut() {
+                *bytes += freed;
+            }
+
+            let ext = extension(&copy.path);
+            let dir = top_dir(&self.roots[copy.root].path, &copy.path);
+            for (is_dir, key) in [(false, ext), (true, dir)] {
+                let breakdowns = if is_dir {
+                    &mut self.top_dirs
+                } else {
+                    &mut self.extensions
+                };
+                let breakdown = breakdowns.entry(key.clone()).or_default();
+                breakdown.copies += 1;
+                if identical {
+                    *breakdown.reclaimable_bytes.get_or_insert(0) += freed;
+                }
+                if group_keys.insert((is_dir, key)) {
+                    breakdown.groups += 1;
+                }
+            }
+        }
+
+        self.groups += 1;
+        self.redundant_copies += copies;
+        if let (Some(total), Some(bytes)) = (self.reclaimable_bytes.as_mut(), group_bytes) {
+            *total += bytes;
+        }
+        self.top_groups.push(GroupStats {
+            first: first.path,
+            copies,
+            size: first.size,
+            reclaimable_bytes: group_bytes,
+        });
+        if self.top_groups.len() > 2 * self.top {
+            self.trim_top_groups();
+        }
+    }
+
+    fn trim_top_groups(&mut self) {
+        self.top_groups
+            .sort_by_key(|g| (Reverse(g.reclaimable_bytes), Reverse(g.size)));
+        self.top_groups.truncate(self.top);
+    }
+
+    /// Sorts the breakdowns and keeps the `top` largest groups.
+    pub fn finish(&mut self) {
+        self.trim_top_groups();
+        for (breakdowns, out) in [
+            (&mut self.extensions, &mut self.by_extension),
+            (&mut self.top_dirs, &mut self.by_top_dir),
+        ] {
+            *out = breakdowns
+                .drain()
+                .map(|(key, breakdown)| Breakdown { key, ..breakdown })
+                .collect();
+            out.sort_by(|a, b| {
+                (Reverse(a.reclaimable_bytes), Reverse(a.copies), &a.key).cmp(&(
+                    Reverse(b.reclaimable_bytes),
+                    Reverse(b.copies),
+                    &b.key,
+                ))
+            });
+        }
+    }
+
+    pub fn print(&self) {
+        println!("Statistics:");
+        for root in self.roots.iter() {
+            println!(
+                "  {}: {} files, {}",
+                root.path,
+                root.files,
+                human_bytes(root.bytes)
+            );
+        }
+        println!("  Duplicate groups: {}", self.groups);
+        println!("  Redundant copies: {}", self.redundant_copies);
+        println!("  Reclaimable: {}", bytes_or_na(self.reclaimable_bytes));
+        if !self.top_groups.is_empty() {
+            println!("  Largest groups:");
+            for group in self.top_groups.iter() {
+                println!(
+                    "    {} in {} copies of {}",
+                    bytes_or_na(group.reclaimable_bytes),
+                    group.copies,
+                    group.first
+                );
+            }
+        }
+        for (title, breakdowns) in [
+            ("By extension", &self.by_extension),
+            ("By top-level dir", &self.by_top_dir),
+        ] {
+            if breakdowns.is_empty() {
+                continue;
+            }
+            println!("  {}:", title);
+            for breakdown in breakdowns.iter().take(self.top) {
+                println!(
+                    "    {}: {} copies in {} groups, {}",
+                    breakdown.key,
+                    breakdown.copies,
+                    breakdown.groups,
+                    bytes_or_na(breakdown.reclaimable_bytes)
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{args::Scope, testing::TempDir};
+
+    fn duplicate<'a>(first: &'a str, copies: &[&str]) -> Duplicate<'a> {
+        Duplicate {
+            file_name: first,
+            first_dir_match: first,
+            second_dir_match: copies.iter().map(|c| c.to_string()).collect(),
+            scope: Scope::IntraRoot,
+            roots: vec![0; copies.len() + 1],
+            decompressed: Vec::new(),
+            metadata_mismatches: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn counts_what_deleting_copies_frees() {
+        let tmp = TempDir::new("stats-reclaim");
+        let mut dir = tmp.dir(
+            "root",
+            &[
+                ("a.txt", b"0123456789"),
+                ("sub/b.txt", b"0123456789"),
+                ("c.bin", b"xyz"),
+                ("d.bin", b"xyz"),
+                ("empty", b""),
+                ("empty-2", b""),
+            ],
+        );
+        let link = tmp.path.join("root/sub/link.txt");
+        fs::hard_link(tmp.path.join("root/a.txt"), &link).unwrap();
+        dir.files.push(crate::testing::file(&link, 10));
+        let root = dir.name.clone();
+        let path = |name: &str| format!("{}/{}", root, name);
+        let (a, b, link) = (path("a.txt"), path("sub/b.txt"), path("sub/link.txt"));
+        let (c, d) = (path("c.bin"), path("d.bin"));
+        let (empty, empty_2) = (path("empty"), path("empty-2"));
+        let duplicates = vec![
+            duplicate(&a, &[&b, &link]),
+            duplicate(&c, &[&d]),
+            duplicate(&empty, &[&empty_2]),
+        ];
+        let dirs = vec![dir];
+
+        let stats = duplicate_stats(&dirs, &duplicates, 2, true);
+        assert_eq!(stats.roots[0].files, 7);
+        assert_eq!(stats.roots[0].bytes, 36);
+        assert_eq!((stats.groups, stats.redundant_copies), (3, 4));
+        // The hard link to the kept copy frees nothing.
+        assert_eq!(stats.reclaimable_bytes, Some(13));
+        let top: Vec<(&str, Option<u64>)> = stats
+            .top_groups
+            .iter()
+            .map(|g| (g.first.as_str(), g.reclaimable_bytes))
+            .collect();
+        assert_eq!(top, vec![(a.as_str(), Some(10)), (c.as_str(), Some(3))]);
+        let sub = stats
+            .by_top_dir
+            .iter()
+            .find(|b| b.key == path("sub"))
+            .unwrap();
+        assert_eq!(
+            (sub.groups, sub.copies, sub.reclaimable_bytes),
+            (1, 2, Some(10))
+        );
+
+        let stats = duplicate_stats(&dirs, &duplicates, 2, false);
+        assert_eq!(stats.reclaimable_bytes, None);
+        assert_eq!(stats.redundant_copies, 4);
+    }
+
+    #[test]
+    fn leaves_archive_members_out() {
+        let tmp = TempDir::new("stats-members");
+        let mut dir = tmp.dir("root", &[("a.txt", b"abc")]);
+        let member = format!("{}/x.zip!/a.txt", dir.name);
+        dir.files.push(crate::testing::file(Path::new(&member), 3));
+        let a = format!("{}/a.txt", dir.name);
+        let duplicates = vec![duplicate(&a, &[&member])];
+        let dirs = vec![dir];
+
+        let stats = duplicate_stats(&dirs, &duplicates, 10, true);
+        assert_eq!(stats.roots[0].files, 1);
+        assert_eq!(stats.redundant_copies, 1);
+        assert_eq!(stats.reclaimable_bytes, Some(0));
+    }
+}
@@ -0,0 +1,205 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+use crate::{args::Scope, Dir};
+
+/// A dir whose names and contents were fully read during the walk.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subtree {
+    pub path: String,
+    pub fingerprint: String,
+    pub file_count: usize,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DirDuplicate<'a> {
+    pub dir_name: &'a str,
+    pub first_dir_match: &'a str,
+    pub second_dir_match: Vec<String>,
+    pub scope: Scope,
+    /// The root of every dir, the first one first.
+    pub roots: Vec<usize>,
+    pub file_count: usize,
+    pub size: u64,
+}
+
+/// Combines `(kind, name, fingerprint)` of dir children into the dir
+/// fingerprint. Children are sorted so that read order does not matter.
+pub fn fingerprint(mut children: Vec<(char, String, String)>) -> String {
+    children.sort();
+    let mut hasher = Sha256::new();
+    for (kind, name, fp) in children {
+        hasher.update(format!("{} {} {}\n", kind, name, fp));
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Groups identical non-empty subtrees of all dirs, keeping the groups of
+/// `scope`. A group is left out when every one of its members sits inside
+/// another duplicated subtree.
+pub fn find_dir_duplicates(dirs: &[Dir], scope: Option<Scope>) -> Vec<DirDuplicate<'_>> {
+    let mut groups: HashMap<&str, Vec<(usize, &Subtree)>> = HashMap::new();
+    for (root, dir) in dirs.iter().enumerate() {
+        for subtree in dir.subtrees.iter().filter(|s| s.file_count > 0) {
+            groups
+                .entry(subtree.fingerprint.as_str())
+                .or_default()
+                .push((root, subtree));
+        }
+    }
+    let groups: Vec<Vec<(usize, &Subtree)>> = groups
+        .into_values()
+        .filter(|members| members.len() > 1)
+        .flat_map(|mut members| {
+            members.sort_by(|a, b| (a.0, &a.1.path).cmp(&(b.0, &b.1.path)));
+            Scope::split(scope, members)
+        })
+        .collect();
+
+    let duplicated: HashSet<&Path> = groups
+        .iter()
+        .flatten()
+        .map(|(_, s)| Path::new(s.path.as_str()))
+        .collect();
+
+    let mut dir_dups: Vec<DirDuplicate> = groups
+        .into_iter()
+        .filter(|members| {
+            !members.iter().all(|(_, s)| {
+                Path::new(&s.path)
+                    .parent()
+                    .is_some_and(|p| duplicated.contains(p))
+            })
+        })
+        .map(|members| {
+            let first = members[0].1;
+            DirDuplicate {
+                dir_name: Path::new(&first.path)
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or(&first.path),
+                first_dir_match: &first.path,
+                second_dir_match: members[1..].iter().map(|(_, s)| s.path.clone()).collect(),
+                scope: Scope::of(members.iter().map(|(root, _)| *root)),
+                roots: members.iter().map(|(root, _)| *root).collect(),
+                file_count: first.file_count,
+                size: first.size,
+            }
+        })
+        .collect();
+    dir_dups.sort_by_key(|d| std::cmp::Reverse(d.size));
+    dir_dups
+}
+
+/// The dirs of the duplicated subtrees, looked up by the ancestors of a path
+/// so that telling whether a file lies inside one takes its depth, not the
+/// number of subtrees.
+pub struct Covered<'a> {
+    dirs: HashSet<&'a Path>,
+}
+
+impl<'a> Covered<'a> {
+    pub fn new(dir_dups: &'a [DirDuplicate]) -> Self {
+        let dirs = dir_dups
+            .iter()
+            .flat_map(|d| {
+                std::iter::once(Path::new(d.first_dir_match))
+                    .chain(d.second_dir_match.iter().map(Path::new))
+            })
+            .collect();
+        Self { dirs }
+    }
+
+    /// Tells whether `path` lies inside one of the duplicated subtrees.
+    pub fn contains(&self, path: &str) -> bool {
+        Path::new(path)
+            .ancestors()
+            .any(|dir| self.dirs.contains(dir))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        args::{MatchBy, Options},
+        testing::TempDir,
+        walk_dir,
+    };
+    use std::os::unix::fs::symlink;
+
+    fn walk(tmp: &TempDir, name: &str) -> Dir {
+        let options = Options {
+            match_by: MatchBy::Content,
+            dirs: true,
+            ..Default::default()
+        };
+        let path = tmp.path.join(name);
+        let mut files = Vec::new();
+        let mut subtrees = Vec::new();
+        walk_dir(&path, &mut files, Some(&mut subtrees), &options).unwrap();
+        Dir::new(path.display().to_string(), files, subtrees)
+    }
+
+    fn relative(tmp: &TempDir, path: &str) -> String {
+        Path::new(path)
+            .strip_prefix(&tmp.path)
+            .unwrap()
+            .display()
+            .to_string()
+    }
+
+    #[test]
+    fn fingerprint_ignores_read_order() {
+        let a = ('f', "a".to_string(), "1".to_string());
+        let b = ('d', "b".to_string(), "2".to_string());
+        assert_eq!(
+            fingerprint(vec![a.clone(), b.clone()]),
+            fingerprint(vec![b.clone(), a.clone()])
+        );
+        assert_ne!(fingerprint(vec![a.clone()]), fingerprint(vec![a, b]));
+    }
+
+    #[test]
+    fn reports_the_outermost_duplicated_dirs() {
+        let tmp = TempDir::new("subtree-nested");
+        for root in ["one", "two"] {
+            tmp.file(&format!("{}/photos/2020/a.jpg", root), b"a");
+            tmp.file(&format!("{}/photos/b.jpg", root), b"b");
+        }
+        tmp.file("one/c.txt", b"c");
+        let dirs = vec![walk(&tmp, "one"), walk(&tmp, "two")];
+
+        let dups = find_dir_duplicates(&dirs, None);
+        assert_eq!(dups.len(), 1);
+        assert_eq!(relative(&tmp, dups[0].first_dir_match), "one/photos");
+        assert_eq!(relative(&tmp, &dups[0].second_dir_match[0]), "two/photos");
+        assert_eq!((dups[0].file_count, dups[0].size), (2, 2));
+        assert_eq!(dups[0].scope, Scope::CrossRoot);
+        assert!(find_dir_duplicates(&dirs, Some(Scope::IntraRoot)).is_empty());
+    }
+
+    #[test]
+    fn fingerprints_symlinks_by_their_target() {
+        let tmp = TempDir::new("subtree-symlinks");
+        for (dir, target) in [("a", "../d"), ("b", "../d"), ("c", "../e")] {
+            tmp.file(&format!("root/{}/f", dir), b"content");
+            symlink(target, tmp.path.join(format!("root/{}/dir-link", dir))).unwrap();
+            symlink("nowhere", tmp.path.join(format!("root/{}/dangling", dir))).unwrap();
+        }
+        tmp.file("root/d/g", b"g");
+        let dirs = vec![walk(&tmp, "root")];
+        assert!(dirs[0].subtrees.iter().any(|s| s.path == dirs[0].name));
+
+        let dups = find_dir_duplicates(&dirs, None);
+        assert_eq!(dups.len(), 1);
+        assert_eq!(relative(&tmp, dups[0].first_dir_match), "root/a");
+        assert_eq!(dups[0].second_dir_match.len(), 1);
+        assert_eq!(relative(&tmp, &dups[0].second_dir_match[0]), "root/b");
+    }
+}
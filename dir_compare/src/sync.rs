@@ -0,0 +1,350 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Write},
+    os::unix::fs::{fchown, symlink, MetadataExt},
+    path::{Path, PathBuf},
+};
+use xattr::FileExt;
+
+use crate::{
+    archive::is_member,
+    args::Options,
+    cancel,
+    diff::{DiffEntry, Status},
+    hash::{feed, hash_content},
+    progress, Dir, File,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Copied,
+    /// Hard-linked to a copy of the same content made earlier.
+    Linked,
+    /// Would be copied, on a dry run.
+    Planned,
+    /// Not copied, since its content is already there under another name.
+    Skipped,
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Transfer<'a> {
+    /// Path relative to both roots.
+    pub path: String,
+    pub from: &'a str,
+    pub to: String,
+    pub size: u64,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub same_content_as: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Copies `from` to `to` through a temporary file next to it, keeping its
+/// mode, mtime and extended attributes, and its owner when allowed to.
+/// Symlinks are copied as links.
+pub fn copy_file(from: &Path, to: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(from)?;
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = to.with_file_name(format!(".{}.dir_compare-tmp", name));
+    let copied = if metadata.file_type().is_symlink() {
+        fs::read_link(from).and_then(|target| symlink(target, &tmp))
+    } else {
+        copy_content(from, &tmp, &metadata)
+    };
+    match copied.and_then(|_| fs::rename(&tmp, to)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+fn copy_content(from: &Path, to: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    let mut out = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(to)?;
+    let mut write_err = None;
+    feed(fs::File::open(from)?, |bytes| {
+        if write_err.is_none() {
+            write_err = out.write_all(bytes).err();
+        }
+    })?;
+    if let Some(e) = write_err {
+        return Err(e);
+    }
+    out.set_permissions(metadata.permissions())?;
+    // Only root may give files away, so a failed chown is not an error.
+    let _ = fchown(&out, Some(metadata.uid()), Some(metadata.gid()));
+    if let Ok(names) = xattr::list(from) {
+        for name in names {
+            if let Ok(Some(value)) = xattr::get(from, &name) {
+                if let Err(e) = out.set_xattr(&name, &value) {
+                    eprintln!("Xattr err: {}, File {:?}", e, to);
+                }
+            }
+        }
+    }
+    let mut times = fs::FileTimes::new();
+    if let Ok(modified) = metadata.modified() {
+        times = times.set_modified(modified);
+    }
+    if let Ok(accessed) = metadata.accessed() {
+        times = times.set_accessed(accessed);
+    }
+    out.set_times(times)
+}
+
+/// Finds, among the files of `by_size`, one with the same bytes as `file`.
+/// Files are only hashed when their size matches, and once, into `hashes`.
+/// Empty files are left alone, since any two of them would match.
+pub fn same_content<'a>(
+    file: &'a File,
+    by_size: &HashMap<u64, Vec<&'a File>>,
+    hashes: &mut HashMap<&'a str, Option<String>>,
+) -> Option<&'a str> {
+    if file.size == 0 {
+        return None;
+    }
+    let others = by_size.get(&file.size)?;
+    let mut hash_of = |file: &'a File| {
+        hashes
+            .entry(&file.path)
+            .or_insert_with(|| {
+                hash_content(Path::new(&file.path), false)
+                    .ok()
+                    .map(|c| c.hash)
+            })
+            .clone()
+    };
+    let hash = hash_of(file)?;
+    others
+        .iter()
+        .find(|other| hash_of(other).as_ref() == Some(&hash))
+        .map(|other| other.path.as_str())
+}
+
+/// Copies the files of `left` that `entries` find missing from `right` to the
+/// same relative path under it. Files moved or renamed on the right count as
+/// missing from their old path, unless `--skip-existing-content` is given,
+/// which also skips any file whose bytes are somewhere under `right`.
+pub fn sync_dirs<'a>(
+    entries: &[DiffEntry<'a>],
+    left: &'a Dir,
+    right: &'a Dir,
+    options: &Options,
+) -> Vec<Transfer<'a>> {
+    let left_files: HashMap<&str, &File> =
+        left.files.iter().map(|f| (f.path.as_str(), f)).collect();
+    let mut right_by_size: HashMap<u64, Vec<&File>> = HashMap::new();
+    if options.skip_existing_content {
+        for file in right.files.iter().filter(|f| !is_member(&f.path)) {
+            right_by_size.entry(file.size).or_default().push(file);
+        }
+    }
+    let mut right_hashes: HashMap<&str, Option<String>> = HashMap::new();
+
+    let missing: Vec<(&String, &File, Option<&str>)> = entries
+        .iter()
+        .filter_map(|entry| {
+            let (path, moved_to) = match entry.status {
+                Status::OnlyLeft => (&entry.path, None),
+                Status::Moved | Status::Renamed => (entry.old_path.as_ref()?, entry.right),
+                _ => return None,
+            };
+            let file = left_files.get(entry.left?)?;
+            (!is_member(&file.path)).then_some((path, *file, moved_to))
+        })
+        .collect();
+    let bytes_total = missing.iter().map(|(_, file, _)| file.size).sum();
+    progress::set_phase("sync", Some(bytes_total));
+
+    let mut transfers = Vec::new();
+    for (path, file, moved_to) in missing {
+        if cancel::stopped() {
+            break;
+        }
+        let to = PathBuf::from(&right.name).join(path);
+        let mut transfer = Transfer {
+            path: path.clone(),
+            from: &file.path,
+            to: to.display().to_string(),
+            size: file.size,
+            outcome: Outcome::Planned,
+            same_content_as: None,
+            error: None,
+        };
+        if options.skip_existing_content {
+            let existing =
+                moved_to.or_else(|| same_content(file, &right_by_size, &mut right_hashes));
+            if let Some(existing) = existing {
+                transfer.outcome = Outcome::Skipped;
+                transfer.same_content_as = Some(existing.to_string());
+                transfers.push(transfer);
+                continue;
+            }
+        }
+        if fs::symlink_metadata(&to).is_ok() {
+            eprintln!("Copy err: target exists, File {:?}", to);
+            progress::error();
+            transfer.outcome = Outcome::Failed;
+            transfer.error = Some("target exists".into());
+        } else if !options.dry_run {
+            match copy_file(Path::new(&file.path), &to) {
+                Ok(()) => transfer.outcome = Outcome::Copied,
+                Err(e) => {
+                    if !cancel::stopped() {
+                        eprintln!("Copy err: {}, File {:?}", e, file.path);
+                        progress::error();
+                    }
+                    transfer.outcome = Outcome::Failed;
+                    transfer.error = Some(e.to_string());
+                }
+            }
+        }
+        transfers.push(transfer);
+    }
+    transfers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{diff::diff_dirs, testing::TempDir};
+    use std::{
+        os::unix::fs::PermissionsExt,
+        time::{Duration, SystemTime},
+    };
+
+    /// The transfers as (path, outcome, same content as), the last relative
+    /// to the right dir.
+    fn plan(left: &Dir, right: &Dir, options: &Options) -> Vec<(String, Outcome, Option<String>)> {
+        let entries = diff_dirs(left, right, false);
+        sync_dirs(&entries, left, right, options)
+            .into_iter()
+            .map(|t| {
+                let existing = t.same_content_as.map(|p| {
+                    let rel = Path::new(&p).strip_prefix(&right.name).unwrap();
+                    rel.display().to_string()
+                });
+                (t.path, t.outcome, existing)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn plans_what_the_right_dir_misses_on_a_dry_run() {
+        let tmp = TempDir::new("sync-plan");
+        let left = tmp.dir(
+            "left",
+            &[
+                ("a/new", b"new"),
+                ("changed", b"left"),
+                ("moved", b"moved"),
+                ("same", b"same"),
+            ],
+        );
+        let right = tmp.dir(
+            "right",
+            &[
+                ("b/moved", b"moved"),
+                ("changed", b"right!"),
+                ("same", b"same"),
+            ],
+        );
+        let options = Options {
+            dry_run: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            plan(&left, &right, &options),
+            [
+                ("a/new".to_string(), Outcome::Planned, None),
+                ("moved".to_string(), Outcome::Planned, None),
+            ]
+        );
+        assert!(!tmp.path.join("right/a").exists());
+        assert!(!tmp.path.join("right/moved").exists());
+    }
+
+    #[test]
+    fn skips_content_the_right_dir_already_holds() {
+        let tmp = TempDir::new("sync-skip");
+        let left = tmp.dir(
+            "left",
+            &[
+                ("a", b"data"),
+                ("b", b"data"),
+                ("empty", b""),
+                ("moved", b"moved"),
+                ("new", b"new"),
+            ],
+        );
+        let right = tmp.dir("right", &[("a", b"data"), ("sub/moved", b"moved")]);
+        let options = Options {
+            dry_run: true,
+            skip_existing_content: true,
+            ..Options::default()
+        };
+        assert_eq!(
+            plan(&left, &right, &options),
+            [
+                ("b".to_string(), Outcome::Skipped, Some("a".to_string())),
+                ("empty".to_string(), Outcome::Planned, None),
+                ("new".to_string(), Outcome::Planned, None),
+                (
+                    "moved".to_string(),
+                    Outcome::Skipped,
+                    Some("sub/moved".to_string())
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn copies_files_with_their_mode_and_mtime() {
+        let tmp = TempDir::new("sync-copy");
+        let left = tmp.dir("left", &[("a/new", b"new"), ("taken", b"left")]);
+        let right = tmp.dir("right", &[]);
+        let from = tmp.path.join("left/a/new");
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o640)).unwrap();
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        fs::File::options()
+            .write(true)
+            .open(&from)
+            .unwrap()
+            .set_modified(mtime)
+            .unwrap();
+
+        let entries = diff_dirs(&left, &right, false);
+        // Written after the diff, so that the copy would replace it.
+        tmp.file("right/taken", b"right");
+        let transfers = sync_dirs(&entries, &left, &right, &Options::default());
+        let outcomes: Vec<(&str, Outcome)> = transfers
+            .iter()
+            .map(|t| (t.path.as_str(), t.outcome))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [("a/new", Outcome::Copied), ("taken", Outcome::Failed)]
+        );
+        assert_eq!(transfers[1].error.as_deref(), Some("target exists"));
+
+        let to = tmp.path.join("right/a/new");
+        assert_eq!(fs::read(&to).unwrap(), b"new");
+        let metadata = fs::metadata(&to).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+        assert_eq!(metadata.modified().unwrap(), mtime);
+        assert_eq!(fs::read(tmp.path.join("right/taken")).unwrap(), b"right");
+        assert!(!tmp.path.join("right/a/.new.dir_compare-tmp").exists());
+    }
+}
@@ -0,0 +1,59 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+use crate::{Dir, File};
+
+/// A scratch dir under the system temp dir, removed when dropped.
+pub struct TempDir {
+    pub path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("dir_compare-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+        Self { path }
+    }
+
+    /// Writes `content` to `rel` under the dir and returns it as walked.
+    pub fn file(&self, rel: &str, content: &[u8]) -> File {
+        let path = self.path.join(rel);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, content).unwrap();
+        file(&path, content.len() as u64)
+    }
+
+    /// A dir of files written under `name`, each a path and its content.
+    pub fn dir(&self, name: &str, files: &[(&str, &[u8])]) -> Dir {
+        let files = files
+            .iter()
+            .map(|(rel, content)| self.file(&format!("{}/{}", name, rel), content))
+            .collect();
+        let path = self.path.join(name);
+        fs::create_dir_all(&path).unwrap();
+        Dir::new(path.display().to_string(), files, Vec::new())
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// A file at `path` of `size` bytes, as a walk would list it.
+pub fn file(path: &Path, size: u64) -> File {
+    File {
+        name: path.file_name().unwrap().to_str().unwrap().to_string(),
+        path: path.display().to_string(),
+        size,
+        modified: None,
+        hash: None,
+        decompressed: false,
+        meta: None,
+    }
+}
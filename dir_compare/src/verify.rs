@@ -0,0 +1,392 @@
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{
+    archive::is_member,
+    cancel,
+    hash::{crc32_file, hash_content, md5_file},
+    progress, Dir, File,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Algorithm {
+    Sha256,
+    Md5,
+    Crc32,
+}
+
+impl Algorithm {
+    /// Tells the algorithm of a GNU style line from the length of its digest.
+    fn of_digest(digest: &str) -> Option<Self> {
+        match digest.len() {
+            64 => Some(Algorithm::Sha256),
+            32 => Some(Algorithm::Md5),
+            _ => None,
+        }
+    }
+
+    fn of_tag(tag: &str) -> Option<Self> {
+        match tag {
+            "SHA256" => Some(Algorithm::Sha256),
+            "MD5" => Some(Algorithm::Md5),
+            "CRC32" => Some(Algorithm::Crc32),
+            _ => None,
+        }
+    }
+
+    /// Hashes the raw bytes of the file at `path`. SHA-256 goes through the
+    /// content hasher, so that checkpointed hashes are reused.
+    fn hash(self, path: &Path) -> io::Result<String> {
+        match self {
+            Algorithm::Sha256 => hash_content(path, false).map(|content| content.hash),
+            Algorithm::Md5 => md5_file(path),
+            Algorithm::Crc32 => crc32_file(path),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Ok,
+    /// Listed in a checksum file but not in the tree.
+    Missing,
+    /// In the tree, next to a checksum file that doesn't list it.
+    Extra,
+    Corrupted,
+    /// Content wasn't checked, because the scan stopped or the file couldn't
+    /// be read.
+    Unchecked,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Check {
+    /// Path relative to the verified dir.
+    pub path: String,
+    pub status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub checksum_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub algorithm: Option<Algorithm>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expected: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actual: Option<String>,
+}
+
+/// A file listed in a checksum file, with its path resolved.
+struct Listed {
+    path: PathBuf,
+    algorithm: Algorithm,
+    digest: String,
+}
+
+/// Tells whether `name` is a checksum file, going by the names sha256sum,
+/// md5sum and SFV tools give them.
+pub fn is_checksum_file(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name == "sha256sums"
+        || name == "md5sums"
+        || [".sha256", ".sha256sum", ".md5", ".md5sum", ".sfv"]
+            .iter()
+            .any(|ext| name.ends_with(ext))
+}
+
+/// Undoes the escaping sha256sum applies to names with a `\` or a newline.
+fn unescape(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                out.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                out.push('\\');
+                chars.next();
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Parses one line of a checksum file into a name, an algorithm and a
+/// digest. SFV lines are `name crc32`, others are either GNU style
+/// `digest  name`, with a `*` before binary names, or BSD style
+/// `ALGO (name) = digest`.
+fn parse_line(line: &str, sfv: bool) -> Option<(String, Algorithm, String)> {
+    let (name, algorithm, digest) = if sfv {
+        let (name, digest) = line.rsplit_once(char::is_whitespace)?;
+        (name.trim_end().to_string(), Algorithm::Crc32, digest)
+    } else if let Some((head, digest)) = line.rsplit_once(") = ") {
+        let (tag, name) = head.split_once(" (")?;
+        (name.to_string(), Algorithm::of_tag(tag)?, digest)
+    } else {
+        let (escaped, line) = match line.strip_prefix('\\') {
+            Some(line) => (true, line),
+            None => (false, line),
+        };
+        let (digest, rest) = line.split_once(' ')?;
+        let name = rest.strip_prefix([' ', '*'])?;
+        let name = if escaped {
+            unescape(name)
+        } else {
+            name.to_string()
+        };
+        (name, Algorithm::of_digest(digest)?, digest)
+    };
+    let valid_len = match algorithm {
+        Algorithm::Sha256 => 64,
+        Algorithm::Md5 => 32,
+        Algorithm::Crc32 => 8,
+    };
+    if name.is_empty()
+        || digest.len() != valid_len
+        || !digest.chars().all(|c| c.is_ascii_hexdigit())
+    {
+        return None;
+    }
+    Some((name, algorithm, digest.to_lowercase()))
+}
+
+/// Drops `.` components and resolves `..` against the name before it, so
+/// that paths read from checksum files compare equal to walked ones. A `..`
+/// at the start of a relative path is kept.
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match (component, out.components().next_back()) {
+            (Component::CurDir, _) => (),
+            (Component::ParentDir, Some(Component::Normal(_))) => {
+                out.pop();
+            }
+            (Component::ParentDir, Some(Component::RootDir)) => (),
+            (component, _) => out.push(component),
+        }
+    }
+    out
+}
+
+/// Tells whether `checksum_file` only lists the file it is named after, like
+/// `foo.iso.md5` next to `foo.iso`, rather than standing for its whole dir.
+fn is_sidecar(checksum_file: &Path, entries: &[Listed]) -> bool {
+    let named = checksum_file.with_extension("");
+    !entries.is_empty() && entries.iter().all(|entry| entry.path == named)
+}
+
+/// Reads the files listed in `checksum_file`, their names taken relative to
+/// `base`. Lines that can't be parsed are reported and skipped.
+fn read_checksums(checksum_file: &Path, base: &Path) -> io::Result<Vec<Listed>> {
+    let text = fs::read_to_string(checksum_file)?;
+    let sfv = checksum_file
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("sfv"));
+    let mut listed = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') || (sfv && line.starts_with(';')) {
+            continue;
+        }
+        match parse_line(line, sfv) {
+            Some((name, algorithm, digest)) => listed.push(Listed {
+                path: normalize(&base.join(name)),
+                algorithm,
+                digest,
+            }),
+            None => {
+                eprintln!(
+                    "Checksum err: invalid line {}, File {:?}",
+                    idx + 1,
+                    checksum_file
+                );
+                progress::error();
+            }
+        }
+    }
+    Ok(listed)
+}
+
+/// Verifies the files of `dir` against the checksum files found in it, each
+/// listing names relative to its own dir, and against `given` ones, listing
+/// names relative to the root. Every file under the dir of a checksum file
+/// that none of them lists is reported as extra, unless the checksum file
+/// only lists the file it is named after.
+pub fn verify_dir(dir: &Dir, given: &[PathBuf]) -> Result<Vec<Check>, String> {
+    let root = normalize(Path::new(&dir.name));
+    let files: HashMap<PathBuf, &File> = dir
+        .files
+        .iter()
+        .filter(|f| !is_member(&f.path))
+        .map(|f| (normalize(Path::new(&f.path)), f))
+        .collect();
+
+    let mut sources: Vec<(PathBuf, PathBuf)> = given
+        .iter()
+        .map(|path| (normalize(path), root.clone()))
+        .collect();
+    for (path, file) in files.iter() {
+        let is_given = sources.iter().any(|(given, _)| given == path);
+        if is_checksum_file(&file.name) && !is_given {
+            let base = path.parent().unwrap_or(Path::new("")).to_path_buf();
+            sources.push((path.clone(), base));
+        }
+    }
+    if sources.is_empty() {
+        return Err(format!("No checksum files found in {}", dir.name));
+    }
+    sources.sort();
+
+    let mut listed: Vec<(String, Listed)> = Vec::new();
+    let mut manifest_dirs: Vec<&PathBuf> = Vec::new();
+    for (checksum_file, base) in sources.iter() {
+        match read_checksums(checksum_file, base) {
+            Ok(entries) => {
+                if !is_sidecar(checksum_file, &entries) {
+                    manifest_dirs.push(base);
+                }
+                let name = checksum_file.display().to_string();
+                listed.extend(entries.into_iter().map(|entry| (name.clone(), entry)));
+            }
+            Err(e) => {
+                eprintln!("Checksum err: {}, File {:?}", e, checksum_file);
+                progress::error();
+            }
+        }
+    }
+
+    let relative = |path: &Path| {
+        path.strip_prefix(&root)
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    };
+    let bytes_total = listed
+        .iter()
+        .filter_map(|(_, entry)| files.get(&entry.path))
+        .map(|f| f.size)
+        .sum();
+    progress::set_phase("verify", Some(bytes_total));
+
+    let mut checks: Vec<Check> = Vec::new();
+    for (checksum_file, entry) in listed.iter() {
+        let (status, actual) = match files.get(&entry.path) {
+            // A file that wasn't walked may still be there when the walk stopped.
+            None if cancel::stopped() && entry.path.exists() => (Status::Unchecked, None),
+            None => (Status::Missing, None),
+            Some(_) if cancel::stopped() => (Status::Unchecked, None),
+            Some(file) => match entry.algorithm.hash(&entry.path) {
+                Ok(hash) if hash == entry.digest => (Status::Ok, Some(hash)),
+                Ok(hash) => (Status::Corrupted, Some(hash)),
+                Err(e) => {
+                    if !cancel::stopped() {
+                        eprintln!("Hash err: {}, File {:?}", e, file.path);
+                        progress::error();
+                    }
+                    (Status::Unchecked, None)
+                }
+            },
+        };
+        checks.push(Check {
+            path: relative(&entry.path),
+            status,
+            checksum_file: Some(checksum_file.clone()),
+            algorithm: Some(entry.algorithm),
+            expected: Some(entry.digest.clone()),
+            actual,
+        });
+    }
+
+    let covered: HashSet<&PathBuf> = listed
+        .iter()
+        .map(|(_, entry)| &entry.path)
+        .chain(sources.iter().map(|(checksum_file, _)| checksum_file))
+        .collect();
+    let mut extra: Vec<&PathBuf> = files
+        .keys()
+        .filter(|path| !covered.contains(path))
+        .filter(|path| manifest_dirs.iter().any(|base| path.starts_with(base)))
+        .collect();
+    extra.sort();
+    checks.extend(extra.into_iter().map(|path| Check {
+        path: relative(path),
+        status: Status::Extra,
+        checksum_file: None,
+        algorithm: None,
+        expected: None,
+        actual: None,
+    }));
+    Ok(checks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855";
+    const MD5: &str = "d41d8cd98f00b204e9800998ecf8427e";
+
+    #[test]
+    fn parses_gnu_lines() {
+        assert_eq!(
+            parse_line(&format!("{}  name with spaces", SHA256), false),
+            Some((
+                "name with spaces".to_string(),
+                Algorithm::Sha256,
+                SHA256.to_lowercase()
+            ))
+        );
+        assert_eq!(
+            parse_line(&format!("{} *binary.iso", MD5), false),
+            Some(("binary.iso".to_string(), Algorithm::Md5, MD5.to_string()))
+        );
+        assert_eq!(
+            parse_line(&format!("\\{}  a\\\\b\\nc", MD5), false),
+            Some(("a\\b\nc".to_string(), Algorithm::Md5, MD5.to_string()))
+        );
+    }
+
+    #[test]
+    fn parses_bsd_lines() {
+        assert_eq!(
+            parse_line(&format!("MD5 (dir/a (1).txt) = {}", MD5), false),
+            Some(("dir/a (1).txt".to_string(), Algorithm::Md5, MD5.to_string()))
+        );
+        assert_eq!(parse_line(&format!("SHA1 (a) = {}", MD5), false), None);
+    }
+
+    #[test]
+    fn parses_sfv_lines() {
+        assert_eq!(
+            parse_line("track 01.flac DEADBEEF", true),
+            Some((
+                "track 01.flac".to_string(),
+                Algorithm::Crc32,
+                "deadbeef".to_string()
+            ))
+        );
+        assert_eq!(parse_line("track.flac DEADBEE", true), None);
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        assert_eq!(parse_line(MD5, false), None);
+        assert_eq!(parse_line(&format!("{}  ", MD5), false), None);
+        assert_eq!(parse_line(&format!("{} name", &MD5[1..]), false), None);
+        assert_eq!(parse_line(&format!("{}x  name", &MD5[1..]), false), None);
+        assert_eq!(parse_line(&format!("{}name", MD5), false), None);
+    }
+
+    #[test]
+    fn normalizes_listed_paths() {
+        assert_eq!(normalize(Path::new("a/./b/../c")), PathBuf::from("a/c"));
+        assert_eq!(normalize(Path::new("../a")), PathBuf::from("../a"));
+        assert_eq!(normalize(Path::new("/../a")), PathBuf::from("/a"));
+    }
+}
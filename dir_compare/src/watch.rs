@@ -0,0 +1,425 @@
+use notify::{
+    event::{AccessKind, AccessMode, ModifyKind},
+    Event, EventKind, RecursiveMode, Watcher,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    sync::mpsc,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    archive::is_member,
+    args::{MatchBy, Options},
+    cancel,
+    decompress::codec_of,
+    hash::hash_content,
+    names::name_key,
+    progress, read_dirs, walk_dir, File,
+};
+
+/// How long a path has to stay quiet before it is looked at, so that files
+/// still being written are not hashed halfway.
+const SETTLE: Duration = Duration::from_secs(1);
+const POLL: Duration = Duration::from_millis(200);
+
+/// Files under the watched dirs, bucketed so that only files of one bucket
+/// can be duplicates: by content size when matching content, by name key
+/// otherwise. Content hashes are only taken once a bucket has two files.
+struct Index<'a> {
+    options: &'a Options,
+    files: BTreeMap<PathBuf, File>,
+    buckets: HashMap<String, BTreeSet<PathBuf>>,
+    bucket_of: HashMap<PathBuf, String>,
+    /// The group of every file known to have duplicates, from the first walk
+    /// or from being reported, by hash or name key. A file is only reported
+    /// again once it joins another group.
+    grouped: BTreeMap<PathBuf, String>,
+}
+
+impl<'a> Index<'a> {
+    fn new(options: &'a Options) -> Self {
+        Self {
+            options,
+            files: BTreeMap::new(),
+            buckets: HashMap::new(),
+            bucket_of: HashMap::new(),
+            grouped: BTreeMap::new(),
+        }
+    }
+
+    fn hash(&mut self, path: &Path) -> Option<String> {
+        let file = self.files.get_mut(path)?;
+        if file.hash.is_none() {
+            match hash_content(path, self.options.decompress) {
+                Ok(content) => {
+                    file.hash = Some(content.hash);
+                    file.decompressed = content.decompressed;
+                }
+                Err(e) => {
+                    if !cancel::stopped() {
+                        eprintln!("Hash err: {}, File {:?}", e, path);
+                        progress::error();
+                    }
+                }
+            }
+        }
+        file.hash.clone()
+    }
+
+    fn bucket(&self, file: &mut File) -> String {
+        match self.options.match_by {
+            MatchBy::Content => {
+                let path = Path::new(&file.path);
+                if self.options.decompress && codec_of(path).is_some() {
+                    if let Ok(content) = hash_content(path, true) {
+                        file.hash = Some(content.hash);
+                        file.decompressed = content.decompressed;
+                        return content.len.to_string();
+                    }
+                }
+                file.size.to_string()
+            }
+            _ => name_key(file, &self.options.name_match),
+        }
+    }
+
+    /// Adds `file` and returns the key of its group with the files already
+    /// indexed that it duplicates.
+    fn add(&mut self, mut file: File) -> (String, Vec<PathBuf>) {
+        let path = PathBuf::from(&file.path);
+        let bucket = self.bucket(&mut file);
+        let others: Vec<PathBuf> = self
+            .buckets
+            .get(&bucket)
+            .map(|paths| paths.iter().filter(|p| **p != path).cloned().collect())
+            .unwrap_or_default();
+        self.files.insert(path.clone(), file);
+        self.buckets
+            .entry(bucket.clone())
+            .or_default()
+            .insert(path.clone());
+        self.bucket_of.insert(path.clone(), bucket.clone());
+
+        if self.options.match_by != MatchBy::Content || others.is_empty() {
+            return (bucket, others);
+        }
+        let Some(hash) = self.hash(&path) else {
+            return (bucket, Vec::new());
+        };
+        let duplicates = others
+            .into_iter()
+            .filter(|other| self.hash(other).as_ref() == Some(&hash))
+            .collect();
+        (hash, duplicates)
+    }
+
+    /// Records that `path` has `duplicates` in the group `key` and tells
+    /// whether it just joined that group, rather than being seen again in
+    /// the group it was known to be in.
+    fn joined(&mut self, path: &Path, key: String, duplicates: &[PathBuf]) -> bool {
+        if duplicates.is_empty() {
+            self.grouped.remove(path);
+            return false;
+        }
+        for other in duplicates {
+            self.grouped.insert(other.clone(), key.clone());
+        }
+        self.grouped.insert(path.to_path_buf(), key.clone()) != Some(key)
+    }
+
+    /// Forgets the groups of files under `path` that are no longer indexed,
+    /// so that they are reported when they turn up again.
+    fn forget_missing(&mut self, path: &Path) {
+        let gone: Vec<PathBuf> = self
+            .grouped
+            .range(path.to_path_buf()..)
+            .map(|(p, _)| p)
+            .take_while(|p| p.starts_with(path))
+            .filter(|p| !self.files.contains_key(*p))
+            .cloned()
+            .collect();
+        for p in gone {
+            self.grouped.remove(&p);
+        }
+    }
+
+    /// Drops `path` and everything below it.
+    fn remove(&mut self, path: &Path) {
+        let gone: Vec<PathBuf> = self
+            .files
+            .range(path.to_path_buf()..)
+            .map(|(p, _)| p)
+            .take_while(|p| p.starts_with(path))
+            .cloned()
+            .collect();
+        for p in gone {
+            self.files.remove(&p);
+            if let Some(bucket) = self.bucket_of.remove(&p) {
+                if let Some(paths) = self.buckets.get_mut(&bucket) {
+                    paths.remove(&p);
+                    if paths.is_empty() {
+                        self.buckets.remove(&bucket);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Runs `command` through the shell with the new file as `$1` and the files
+/// it duplicates after it.
+fn run_action(command: &str, path: &Path, duplicates: &[PathBuf]) {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .arg("dir_compare")
+        .arg(path)
+        .args(duplicates)
+        .status();
+    match status {
+        Ok(status) if !status.success() => {
+            eprintln!("Action err: {}, File {:?}", status, path)
+        }
+        Err(e) => eprintln!("Action err: {}, File {:?}", e, path),
+        _ => (),
+    }
+}
+
+/// Tells whether an event may have changed which files exist or what they hold.
+fn changes_files(kind: &EventKind) -> bool {
+    match kind {
+        EventKind::Create(_) | EventKind::Remove(_) => true,
+        EventKind::Modify(ModifyKind::Metadata(_)) => false,
+        EventKind::Modify(_) => true,
+        EventKind::Access(AccessKind::Close(AccessMode::Write)) => true,
+        _ => false,
+    }
+}
+
+/// Looks at a settled path again: gone paths leave the index, files are
+/// added back and dirs are walked. Returns the files that joined a group on
+/// the way, with their duplicates.
+fn refresh(index: &mut Index, path: &Path) -> Vec<(PathBuf, Vec<PathBuf>)> {
+    index.remove(path);
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        index.forget_missing(path);
+        return Vec::new();
+    };
+    let mut files = Vec::new();
+    if metadata.is_dir() {
+        if let Err(e) = walk_dir(path, &mut files, None, index.options) {
+            eprintln!("Err {:?}, Dir {:?}", e, path);
+            progress::error();
+        }
+    } else {
+        files.push(File {
+            name: path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default()
+                .to_string(),
+            path: path.to_str().expect("Invalid Unicode data").to_string(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            hash: None,
+            decompressed: false,
+            meta: None,
+        });
+    }
+    let joined = files
+        .into_iter()
+        .filter(|file| !is_member(&file.path))
+        .filter_map(|file| {
+            let path = PathBuf::from(&file.path);
+            let (key, duplicates) = index.add(file);
+            index
+                .joined(&path, key, &duplicates)
+                .then_some((path, duplicates))
+        })
+        .collect();
+    index.forget_missing(path);
+    joined
+}
+
+/// Seeds an index from walking `paths` and keeps it up to date from file
+/// system events, reporting every file that turns up as a duplicate of an
+/// indexed one, or joins another group when it changes. Runs until stopped,
+/// and tells whether any duplicate was found.
+pub fn watch(paths: Vec<PathBuf>, options: &Options) -> Result<bool, Box<dyn std::error::Error>> {
+    // Paths are made absolute to match the ones events come with, and watched
+    // before the walk so that nothing written during it is missed.
+    let cwd = std::env::current_dir()?;
+    let paths: Vec<PathBuf> = paths.into_iter().map(|path| cwd.join(path)).collect();
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx)?;
+    for path in paths.iter() {
+        watcher.watch(path, RecursiveMode::Recursive)?;
+    }
+    let dirs = read_dirs(paths, options)?;
+
+    let mut index = Index::new(options);
+    for file in dirs.into_iter().flat_map(|d| d.files) {
+        if !is_member(&file.path) {
+            let path = PathBuf::from(&file.path);
+            let (key, duplicates) = index.add(file);
+            index.joined(&path, key, &duplicates);
+        }
+    }
+    progress::finish();
+    println!("Watching {} files...", index.files.len());
+
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    let mut found = 0;
+    while !cancel::stopped() {
+        match rx.recv_timeout(POLL) {
+            Ok(Ok(Event { kind, paths, .. })) if changes_files(&kind) => {
+                for path in paths {
+                    pending.insert(path, Instant::now());
+                }
+            }
+            Ok(Err(e)) => eprintln!("Watch err: {}", e),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break,
+            _ => (),
+        }
+
+        let settled: Vec<PathBuf> = pending
+            .iter()
+            .filter(|(_, at)| at.elapsed() >= SETTLE)
+            .map(|(path, _)| path.clone())
+            .collect();
+        for path in settled {
+            pending.remove(&path);
+            for (path, mut duplicates) in refresh(&mut index, &path) {
+                // Files removed meanwhile may not have settled yet.
+                duplicates.retain(|p| p.exists());
+                if duplicates.is_empty() {
+                    continue;
+                }
+                found += 1;
+                println!("Duplicate: {:?} of {:?}", path, duplicates);
+                if let Some(command) = options.on_duplicate.as_deref() {
+                    run_action(command, &path, &duplicates);
+                }
+            }
+        }
+    }
+
+    println!("New duplicates: {:?}", found);
+    Ok(found > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn content() -> Options {
+        Options {
+            match_by: MatchBy::Content,
+            ..Options::default()
+        }
+    }
+
+    fn found(joined: Vec<(PathBuf, Vec<PathBuf>)>, tmp: &TempDir) -> Vec<(String, Vec<String>)> {
+        let rel = |p: &Path| p.strip_prefix(&tmp.path).unwrap().display().to_string();
+        joined
+            .iter()
+            .map(|(path, duplicates)| (rel(path), duplicates.iter().map(|d| rel(d)).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn reports_files_that_join_a_group_once() {
+        let tmp = TempDir::new("watch-joins");
+        let options = content();
+        let mut index = Index::new(&options);
+        for file in [tmp.file("a", b"same"), tmp.file("b", b"diff")] {
+            let path = PathBuf::from(&file.path);
+            let (key, duplicates) = index.add(file);
+            assert!(!index.joined(&path, key, &duplicates));
+        }
+
+        tmp.file("c", b"same");
+        let joined = refresh(&mut index, &tmp.path.join("c"));
+        assert_eq!(
+            found(joined, &tmp),
+            [("c".to_string(), vec!["a".to_string()])]
+        );
+        // Seen again with the same content, c stays in its group.
+        assert!(refresh(&mut index, &tmp.path.join("c")).is_empty());
+
+        // Rewritten to the content of b, it joins that group instead.
+        tmp.file("c", b"diff");
+        let joined = refresh(&mut index, &tmp.path.join("c"));
+        assert_eq!(
+            found(joined, &tmp),
+            [("c".to_string(), vec!["b".to_string()])]
+        );
+    }
+
+    #[test]
+    fn reports_files_again_after_they_were_removed() {
+        let tmp = TempDir::new("watch-removes");
+        let options = content();
+        let mut index = Index::new(&options);
+        tmp.file("a", b"same");
+        assert!(refresh(&mut index, &tmp.path).is_empty());
+
+        tmp.file("sub/b", b"same");
+        let joined = refresh(&mut index, &tmp.path.join("sub"));
+        assert_eq!(
+            found(joined, &tmp),
+            [("sub/b".to_string(), vec!["a".to_string()])]
+        );
+
+        fs::remove_dir_all(tmp.path.join("sub")).unwrap();
+        assert!(refresh(&mut index, &tmp.path.join("sub")).is_empty());
+        assert!(!index.files.contains_key(&tmp.path.join("sub/b")));
+        assert!(!index.grouped.contains_key(&tmp.path.join("sub/b")));
+
+        tmp.file("sub/b", b"same");
+        let joined = refresh(&mut index, &tmp.path.join("sub"));
+        assert_eq!(
+            found(joined, &tmp),
+            [("sub/b".to_string(), vec!["a".to_string()])]
+        );
+    }
+
+    #[test]
+    fn buckets_by_name_when_matching_names() {
+        let tmp = TempDir::new("watch-names");
+        let options = Options::default();
+        let mut index = Index::new(&options);
+        tmp.file("one/x.txt", b"1");
+        tmp.file("one/y.txt", b"2");
+        assert!(refresh(&mut index, &tmp.path.join("one")).is_empty());
+
+        tmp.file("two/x.txt", b"3");
+        let joined = refresh(&mut index, &tmp.path.join("two"));
+        assert_eq!(
+            found(joined, &tmp),
+            [("two/x.txt".to_string(), vec!["one/x.txt".to_string()])]
+        );
+        assert!(index.files.values().all(|file| file.hash.is_none()));
+    }
+
+    #[test]
+    fn ignores_metadata_only_events() {
+        use notify::event::{CreateKind, MetadataKind, RemoveKind};
+        assert!(changes_files(&EventKind::Create(CreateKind::File)));
+        assert!(changes_files(&EventKind::Remove(RemoveKind::Any)));
+        assert!(changes_files(&EventKind::Access(AccessKind::Close(
+            AccessMode::Write
+        ))));
+        assert!(!changes_files(&EventKind::Modify(ModifyKind::Metadata(
+            MetadataKind::Permissions
+        ))));
+        assert!(!changes_files(&EventKind::Access(AccessKind::Open(
+            AccessMode::Read
+        ))));
+    }
+}